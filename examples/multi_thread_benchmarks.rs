@@ -13,7 +13,7 @@ use std::time::{Instant};
 use buddy_system_allocator::LockedHeap;
 use core_affinity::CoreId;
 use rand::Rng;
-use rslab::{init_slab_system, SlabAllocator};
+use rslab::{init_slab_system, CpuTopology, FrameProvider, SlabAllocator};
 /// This is already enough to fill the corresponding heaps.
 const BENCH_DURATION: f64 = 10.0;
 /// 160 MiB heap size.
@@ -35,34 +35,38 @@ static mut CURRENT_PAGE: usize = 0;
 
 static mut MAP:BTreeMap<u64,CoreId> = BTreeMap::new();
 
-#[no_mangle]
-unsafe fn free_frames(_addr: *mut u8, num: usize) {
-    USED_PAGES -= num;
-}
+struct PageProvider;
 
-#[no_mangle]
-unsafe fn alloc_frames(num: usize) -> *mut u8 {
-    if (USED_PAGES + num) * 4096 > HEAP_SIZE {
-        return null_mut()
+impl FrameProvider for PageProvider {
+    fn alloc_frames(&self, num: usize) -> *mut u8 {
+        unsafe {
+            if (USED_PAGES + num) * 4096 > HEAP_SIZE {
+                return null_mut()
+            }
+            USED_PAGES += num;
+            let addr = HEAP_PAGE_MEMORY[CURRENT_PAGE].data.as_mut_ptr();
+            CURRENT_PAGE += num;
+            addr
+        }
+    }
+    fn free_frames(&self, _addr: *mut u8, num: usize) {
+        unsafe { USED_PAGES -= num; }
     }
-    USED_PAGES += num;
-    let addr = HEAP_PAGE_MEMORY[CURRENT_PAGE].data.as_mut_ptr();
-    CURRENT_PAGE += num;
-    addr
 }
 
-#[no_mangle]
-fn current_cpu_id()->usize{
-    if unsafe{MAP.is_empty()} {
-        return 0
-    }
-    let current_thread_id = thread::current().id().as_u64().get();
-    let id = unsafe{MAP.get(&current_thread_id)};
-    if id.is_none() {
-        println!("current_thread_id: {:?} not found", current_thread_id);
+impl CpuTopology for PageProvider {
+    fn current_cpu_id(&self) -> usize {
+        if unsafe{MAP.is_empty()} {
+            return 0
+        }
+        let current_thread_id = thread::current().id().as_u64().get();
+        let id = unsafe{MAP.get(&current_thread_id)};
+        if id.is_none() {
+            println!("current_thread_id: {:?} not found", current_thread_id);
+        }
+        let core_id = unsafe{MAP.get(&current_thread_id).unwrap()};
+        core_id.id
     }
-    let core_id = unsafe{MAP.get(&current_thread_id).unwrap()};
-    core_id.id
 }
 
 struct BuddyAllocator{
@@ -103,10 +107,11 @@ unsafe impl Allocator for BuddyAllocator{
 
 
 static ALLOCATOR:BuddyAllocator=BuddyAllocator{back:LockedHeap::empty()};
-static SLAB_ALLOCATOR:SlabAllocator = SlabAllocator;
+static SLAB_ALLOCATOR:SlabAllocator = SlabAllocator::new();
 
 fn main() {
-    init_slab_system(4096, 64);
+    let cpus = core_affinity::get_core_ids().unwrap().len();
+    init_slab_system(4096, 64, cpus, &PageProvider, &PageProvider).unwrap();
     unsafe{
         ALLOCATOR.init(HEAP_MEMORY.0.as_ptr() as usize, HEAP_SIZE)
     }
@@ -227,4 +232,4 @@ struct BenchRunResults {
 }
 
 #[repr(align(4096))]
-struct PageAlignedBytes<const N: usize>([u8; N]);
\ No newline at end of file
+struct PageAlignedBytes<const N: usize>([u8; N]);
\ No newline at end of file
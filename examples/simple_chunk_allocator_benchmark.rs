@@ -34,7 +34,7 @@ use std::ptr::{NonNull, null_mut};
 use good_memory_allocator::DEFAULT_SMALLBINS_AMOUNT;
 use rand::Rng;
 use simple_chunk_allocator::{GlobalChunkAllocator, DEFAULT_CHUNK_SIZE};
-use rslab::{init_slab_system, SlabAllocator};
+use rslab::{init_slab_system, CpuTopology, FrameProvider, SlabAllocator};
 
 /// This is already enough to fill the corresponding heaps.
 const BENCH_DURATION: f64 = 10.0;
@@ -59,24 +59,30 @@ static mut HEAP_PAGE_MEMORY: [Page; HEAP_SIZE*2 / 4096] = [Page{data: [0; 4096]}
 static mut USED_PAGES: usize = 0;
 static mut CURRENT_PAGE: usize = 0;
 /// rslab specific stuff.
-#[no_mangle]
-unsafe fn free_frames(_addr: *mut u8, num: usize) {
-    println!("free_frames: {:p}, {}", _addr, num);
-    USED_PAGES -= num;
-}
-#[no_mangle]
-fn current_cpu_id() -> usize {
-    0
+struct PageProvider;
+
+impl FrameProvider for PageProvider {
+    fn alloc_frames(&self, num: usize) -> *mut u8 {
+        unsafe {
+            if (USED_PAGES + num) * 4096 > HEAP_SIZE {
+                return null_mut()
+            }
+            USED_PAGES += num;
+            let addr = HEAP_PAGE_MEMORY[CURRENT_PAGE].data.as_mut_ptr();
+            CURRENT_PAGE += num;
+            addr
+        }
+    }
+    fn free_frames(&self, _addr: *mut u8, num: usize) {
+        println!("free_frames: {:p}, {}", _addr, num);
+        unsafe { USED_PAGES -= num; }
+    }
 }
-#[no_mangle]
-unsafe fn alloc_frames(num: usize) -> *mut u8 {
-    if (USED_PAGES + num) * 4096 > HEAP_SIZE {
-        return null_mut()
+
+impl CpuTopology for PageProvider {
+    fn current_cpu_id(&self) -> usize {
+        0
     }
-    USED_PAGES += num;
-    let addr = HEAP_PAGE_MEMORY[CURRENT_PAGE].data.as_mut_ptr();
-    CURRENT_PAGE += num;
-    addr
 }
 
 /// buddy allocator specific stuff.
@@ -129,8 +135,8 @@ unsafe impl Allocator for BuddyAllocator{
 /// --example bench --release`
 fn main() {
 
-    init_slab_system(4096,32);
-    let mut slab_allocator = SlabAllocator;
+    init_slab_system(4096,32, 1, &PageProvider, &PageProvider).unwrap();
+    let mut slab_allocator = SlabAllocator::new();
     println!("run slab");
     let bench_res_4 = benchmark_allocator(&mut slab_allocator);
 
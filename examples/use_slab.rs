@@ -1,20 +1,23 @@
 use preprint::Print;
 use std::alloc::{alloc, dealloc, GlobalAlloc, Layout};
 use std::fmt::Arguments;
-use rslab::{init_slab_system, Object, ObjectAllocator, print_slab_system_info, SlabAllocator, SlabCache};
+use rslab::{init_slab_system, CpuTopology, FrameProvider, Object, ObjectAllocator, print_slab_system_info, SlabAllocator, SlabCache};
 
-#[no_mangle]
-unsafe fn free_frames(addr: *mut u8, num: usize) {
-    dealloc(addr, Layout::from_size_align(num * 4096, 4096).unwrap());
-}
-#[no_mangle]
-fn current_cpu_id() -> usize {
-    0
+struct Frames;
+
+impl FrameProvider for Frames {
+    fn alloc_frames(&self, num: usize) -> *mut u8 {
+        unsafe { alloc(Layout::from_size_align(4096 * num, 4096).unwrap()) }
+    }
+    fn free_frames(&self, addr: *mut u8, num: usize) {
+        unsafe { dealloc(addr, Layout::from_size_align(num * 4096, 4096).unwrap()) }
+    }
 }
-#[no_mangle]
-unsafe fn alloc_frames(num: usize) -> *mut u8 {
-    let addr = alloc(Layout::from_size_align(4096 * num, 4096).unwrap());
-    addr
+
+impl CpuTopology for Frames {
+    fn current_cpu_id(&self) -> usize {
+        0
+    }
 }
 
 struct MPrint;
@@ -40,7 +43,8 @@ impl Object for TestObj{
 fn main() {
     // If you want to print rslab usage, you need to initialize this trait object
     preprint::init_print(&MPrint);
-    init_slab_system(4096, 64);
+    // nothing else uses the global slab system yet
+    unsafe { init_slab_system(4096, 64, 1, &Frames, &Frames) }.unwrap();
     use_your_cache();
     unsafe {
         use_common_cache();
@@ -60,13 +64,13 @@ fn use_your_cache() {
     cache.dealloc(ptr1).unwrap();
     print_slab_system_info();
     // destruct your cache
-    cache.destroy();
+    cache.destroy().unwrap();
     // if use cache after destroy, it will panic
     // cache.alloc().unwrap();
 }
 
 unsafe fn use_common_cache(){
-    let slab_allocator = SlabAllocator;
+    let slab_allocator = SlabAllocator::new();
     let layout1 = Layout::from_size_align(4096, 4096).unwrap();
     let addr1 = slab_allocator.alloc(layout1);
     println!("{:p}",addr1);
@@ -1,11 +1,45 @@
 use preprint::pprintln;
 
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum SlabError {
     CantAllocFrame,
     NameDuplicate,
     NotInCache,
     ArrayCacheAllocError,
     SizeTooLarge,
+    /// current_cpu_id返回的id超出了初始化时配置的cpu数量
+    InvalidCpuId,
+    /// 本地高速缓存参数不合法
+    InvalidTunables,
+    /// 不存在指定名称的cache
+    CacheNotFound,
+    /// frame_size不是2的幂、小于MIN_FRAME_SIZE，或者容纳不下slab系统自身的元数据
+    InvalidFrameSize,
+    /// cache_line_size不是2的幂
+    InvalidCacheLineSize,
+    /// cpu数量为0
+    InvalidCpuCount,
+    /// slab系统已经初始化过
+    AlreadyInitialized,
+    /// size class表不合法
+    InvalidSizeClass,
+    /// slab系统尚未初始化或者已经关闭
+    NotInitialized,
+    /// 对齐要求超过了frame_size
+    AlignTooLarge,
+    /// ATOMIC分配无法在不申请页帧的情况下完成
+    WouldBlock,
+    /// 分配标志的组合不合法
+    InvalidFlags,
+    /// 对齐不是2的幂
+    InvalidAlign,
+    /// 指针不在对象的起始位置
+    MisalignedObject,
+    /// 对象被重复释放
+    DoubleFree,
+    /// 对象前后的red zone被改写，释放时返回此错误的对象已经被释放
+    RedZoneCorrupted,
+    /// cache中仍有对象未释放，拒绝销毁
+    CacheInUse,
 }
@@ -1,93 +1,489 @@
-use crate::slab::{alloc_from_slab, create_mem_cache, dealloc_to_slab};
-use crate::SLAB_CACHES;
+use crate::slab::{CacheOptions, DebugFlags, KmallocSlot, MemCache, SlabSystem, Zone};
+use crate::FrameProvider;
+use crate::slab_system;
 use core::alloc::{Allocator, AllocError, GlobalAlloc, Layout};
+use core::cmp::{max, min};
 use core::ptr::NonNull;
 use doubly_linked_list::*;
 use crate::formation::SlabError;
 
-const CACHE_INFO_MAX: usize = 21;
-const KMALLOC_INFO: [&'static str; CACHE_INFO_MAX] = [
-    "malloc-8",
-    "malloc-16",
-    "malloc-32",
-    "malloc-64",
-    "malloc-128",
-    "malloc-256",
-    "malloc-512",
-    "malloc-1024",
-    "malloc-2048",
-    "malloc-4096",
-    "malloc-8192",
-    "malloc_16384",
-    "malloc_32768",
-    "malloc_65536",
-    "malloc_131072",
-    "malloc_262144",
-    "malloc_524288",
-    "malloc_1048576",
-    "malloc_2097152",
-    "malloc_4194304",
-    "malloc_8388608",
+/// size class的最大数量
+pub const SIZE_CLASS_MAX: usize = 64;
+
+/// kmalloc的一个size class，每个size class对应一个cache
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SizeClass {
+    /// cache名称
+    pub name: &'static str,
+    /// 对象大小，需要是8的倍数并且不超过u32::MAX
+    pub size: usize,
+}
+
+impl SizeClass {
+    pub const fn new(name: &'static str, size: usize) -> Self {
+        Self { name, size }
+    }
+
+    /// 对象的自然对齐，即大小中2的幂的因子，最多到frame_size
+    pub(crate) fn align(&self, frame_size: usize) -> usize {
+        min(1 << self.size.trailing_zeros(), frame_size)
+    }
+}
+
+/// 默认的size class，8B-8MB的2的幂
+pub const DEFAULT_SIZE_CLASSES: &[SizeClass] = &[
+    SizeClass::new("malloc-8", 8),
+    SizeClass::new("malloc-16", 16),
+    SizeClass::new("malloc-32", 32),
+    SizeClass::new("malloc-64", 64),
+    SizeClass::new("malloc-128", 128),
+    SizeClass::new("malloc-256", 256),
+    SizeClass::new("malloc-512", 512),
+    SizeClass::new("malloc-1024", 1024),
+    SizeClass::new("malloc-2048", 2048),
+    SizeClass::new("malloc-4096", 4096),
+    SizeClass::new("malloc-8192", 8192),
+    SizeClass::new("malloc_16384", 16384),
+    SizeClass::new("malloc_32768", 32768),
+    SizeClass::new("malloc_65536", 65536),
+    SizeClass::new("malloc_131072", 131072),
+    SizeClass::new("malloc_262144", 262144),
+    SizeClass::new("malloc_524288", 524288),
+    SizeClass::new("malloc_1048576", 1048576),
+    SizeClass::new("malloc_2097152", 2097152),
+    SizeClass::new("malloc_4194304", 4194304),
+    SizeClass::new("malloc_8388608", 8388608),
 ];
 
-pub fn init_kmalloc() {
-    for i in 0..CACHE_INFO_MAX {
-        let info = KMALLOC_INFO[i];
-        create_mem_cache(info, 1 << (i + 3), 8);
+/// kmalloc配置
+#[derive(Clone, Copy)]
+pub struct KmallocConfig {
+    /// 按对象大小递增排列的size class
+    pub size_classes: &'static [SizeClass],
+    /// 超过此大小的请求直接从页帧提供者分配整数个页帧，
+    /// 超过最大size class的请求总是如此
+    pub large_threshold: usize,
+    /// DMA区域的页帧提供者，设置后会创建dma-前缀的kmalloc cache
+    pub dma: Option<&'static dyn FrameProvider>,
+    /// DMA32区域的页帧提供者，设置后会创建dma32-前缀的kmalloc cache
+    pub dma32: Option<&'static dyn FrameProvider>,
+    /// 所有kmalloc cache的调试选项
+    pub debug: DebugFlags,
+}
+
+impl KmallocConfig {
+    pub const fn new() -> Self {
+        Self {
+            size_classes: DEFAULT_SIZE_CLASSES,
+            large_threshold: usize::MAX,
+            dma: None,
+            dma32: None,
+            debug: DebugFlags::empty(),
+        }
+    }
+
+    /// 检查size class是否非空、严格递增、为8的倍数且不超过u32::MAX
+    pub(crate) fn check(&self) -> Result<(), SlabError> {
+        let classes = self.size_classes;
+        if classes.is_empty() || classes.len() > SIZE_CLASS_MAX {
+            return Err(SlabError::InvalidSizeClass);
+        }
+        if classes
+            .iter()
+            .any(|class| class.size == 0 || class.size % 8 != 0 || u32::try_from(class.size).is_err())
+        {
+            return Err(SlabError::InvalidSizeClass);
+        }
+        if classes.windows(2).any(|pair| pair[0].size >= pair[1].size) {
+            return Err(SlabError::InvalidSizeClass);
+        }
+        Ok(())
+    }
+}
+
+impl Default for KmallocConfig {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl core::fmt::Debug for KmallocConfig {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("KmallocConfig")
+            .field("size_classes", &self.size_classes)
+            .field("large_threshold", &self.large_threshold)
+            .field("dma", &self.dma.is_some())
+            .field("dma32", &self.dma32.is_some())
+            .field("debug", &self.debug)
+            .finish()
+    }
+}
+
+/// 小于等于此大小的请求直接查表得到size class
+const SMALL_SIZE_MAX: usize = 4096;
+
+/// 从请求大小到size class下标的映射
+pub(crate) struct SizeIndex {
+    /// 以8字节为粒度，记录能够容纳该大小的第一个size class
+    small: [u8; SMALL_SIZE_MAX / 8],
+    /// 按2的幂分组，记录每组中第一个可能容纳该组大小的size class
+    pow: [u8; usize::BITS as usize + 1],
+    /// size class数量
+    len: u8,
+}
+
+impl SizeIndex {
+    pub(crate) const fn new() -> Self {
+        Self {
+            small: [0; SMALL_SIZE_MAX / 8],
+            pow: [0; usize::BITS as usize + 1],
+            len: 0,
+        }
+    }
+
+    /// 根据size class表建立映射，表需要已经通过检查
+    pub(crate) fn build(&mut self, classes: &[SizeClass]) {
+        let first_fit = |size: usize| {
+            classes
+                .iter()
+                .position(|class| class.size >= size)
+                .unwrap_or(classes.len()) as u8
+        };
+        for (i, index) in self.small.iter_mut().enumerate() {
+            *index = first_fit((i + 1) * 8);
+        }
+        // 第bits组包含(2^(bits-1), 2^bits]的大小
+        for (bits, index) in self.pow.iter_mut().enumerate() {
+            *index = match bits {
+                0 => 0,
+                _ => first_fit((1usize << (bits - 1)) + 1),
+            };
+        }
+        self.len = classes.len() as u8;
+    }
+
+    /// 找到能够容纳size的第一个size class
+    #[inline]
+    pub(crate) fn lookup(&self, classes: &[SizeClass], size: usize) -> Option<usize> {
+        let mut index = if size <= SMALL_SIZE_MAX {
+            self.small[size.saturating_sub(1) / 8] as usize
+        } else {
+            let bits = (usize::BITS - (size - 1).leading_zeros()) as usize;
+            self.pow[bits] as usize
+        };
+        // 同一组内只有少数几个size class
+        while index < self.len as usize && classes[index].size < size {
+            index += 1;
+        }
+        (index < self.len as usize).then_some(index)
+    }
+
+    /// 找到能够容纳size且自然对齐满足align的第一个size class
+    #[inline]
+    pub(crate) fn lookup_aligned(
+        &self,
+        classes: &[SizeClass],
+        size: usize,
+        align: usize,
+        frame_size: usize,
+    ) -> Option<usize> {
+        // 对齐为align的size class大小至少为align
+        let mut index = self.lookup(classes, max(size, align))?;
+        while classes[index].align(frame_size) < align {
+            index += 1;
+            if index == self.len as usize {
+                return None;
+            }
+        }
+        Some(index)
+    }
+}
+
+pub fn init_kmalloc(system: &mut SlabSystem, debug: DebugFlags) -> Result<(), SlabError> {
+    // 每个有页帧提供者的内存区域一组kmalloc cache
+    for zone in Zone::ALL {
+        if !system.has_kmalloc_zone(zone) {
+            continue;
+        }
+        let options = CacheOptions {
+            zone,
+            debug,
+            ..Default::default()
+        };
+        for (index, class) in system.size_classes().iter().enumerate() {
+            let align = class.align(system.frame_size()) as u32;
+            let name = system.kmalloc_cache_name(zone, class.name)?;
+            let cache = system.create_mem_cache(name, class.size as u32, align, options)?;
+            let cache = cache as *mut MemCache;
+            system.set_kmalloc_cache(zone, index, cache);
+        }
+    }
+    Ok(())
+}
+
+/// 分配失败时调用，返回true时会再次尝试分配
+pub type OomHandler = fn(Layout, SlabError) -> bool;
+/// 释放失败时调用
+pub type DeallocErrorHandler = fn(*mut u8, Layout, SlabError);
+
+/// 基于slab系统的分配器
+///
+/// 默认使用全局的slab系统，也可以通过[`SlabAllocator::new_in`]绑定到指定的slab系统。
+/// 分配失败时返回空指针，释放失败时只记录日志，两者都可以设置自定义的处理函数
+#[derive(Clone, Copy)]
+pub struct SlabAllocator {
+    system: Option<&'static SlabSystem>,
+    oom_handler: Option<OomHandler>,
+    dealloc_error_handler: Option<DeallocErrorHandler>,
+}
+
+impl SlabAllocator {
+    /// 使用全局的slab系统
+    pub const fn new() -> Self {
+        Self {
+            system: None,
+            oom_handler: None,
+            dealloc_error_handler: None,
+        }
+    }
+    /// 使用指定的slab系统
+    pub const fn new_in(system: &'static SlabSystem) -> Self {
+        Self {
+            system: Some(system),
+            oom_handler: None,
+            dealloc_error_handler: None,
+        }
+    }
+    /// 设置分配失败时的处理函数
+    ///
+    /// 处理函数可以释放内存后返回true让分配器重试，一直返回true时分配器会一直重试
+    pub const fn with_oom_handler(mut self, handler: OomHandler) -> Self {
+        self.oom_handler = Some(handler);
+        self
+    }
+    /// 设置释放失败时的处理函数
+    pub const fn with_dealloc_error_handler(mut self, handler: DeallocErrorHandler) -> Self {
+        self.dealloc_error_handler = Some(handler);
+        self
+    }
+    /// 分配内存，失败时先交给oom处理函数
+    #[track_caller]
+    fn try_alloc(&self, layout: Layout) -> Result<*mut u8, SlabError> {
+        loop {
+            match self.system().alloc_from_slab(layout) {
+                Ok(ptr) => return Ok(ptr),
+                Err(err) => match self.oom_handler {
+                    Some(handler) if handler(layout, err) => continue,
+                    _ => return Err(err),
+                },
+            }
+        }
+    }
+    /// 释放内存，失败时交给释放错误处理函数
+    fn try_dealloc(&self, ptr: *mut u8, layout: Layout) {
+        if let Err(err) = self.system().dealloc_to_slab(ptr, layout) {
+            match self.dealloc_error_handler {
+                Some(handler) => handler(ptr, layout, err),
+                None => error!("dealloc {:?} {:?} failed: {:?}", ptr, layout, err),
+            }
+        }
+    }
+    #[inline]
+    fn system(&self) -> &'static SlabSystem {
+        match self.system {
+            Some(system) => system,
+            None => slab_system(),
+        }
+    }
+    /// 返回分配的内存实际可用的大小
+    pub fn usable_size(&self, ptr: *const u8) -> Result<usize, SlabError> {
+        self.system().ksize(ptr)
+    }
+    /// 新旧大小由同一个kmalloc cache或同样数量的页帧提供时，可以原地调整大小
+    #[inline]
+    fn same_slot(&self, old: Layout, new: Layout) -> Option<KmallocSlot> {
+        let system = self.system();
+        match (
+            system.kmalloc_slot(kmalloc_layout(old), Zone::Normal),
+            system.kmalloc_slot(kmalloc_layout(new), Zone::Normal),
+        ) {
+            (Ok(old), Ok(new)) if old == new => Some(new),
+            _ => None,
+        }
+    }
+    /// 调整大小，跨size class时才需要重新分配并复制
+    #[track_caller]
+    unsafe fn resize(
+        &self,
+        ptr: NonNull<u8>,
+        old_layout: Layout,
+        new_layout: Layout,
+    ) -> Result<NonNull<[u8]>, AllocError> {
+        if old_layout.size() != 0 && new_layout.size() != 0 {
+            let system = self.system();
+            let _guard = system.enter().map_err(|_| AllocError)?;
+            if let Some(slot) = self.same_slot(old_layout, new_layout) {
+                return Ok(NonNull::slice_from_raw_parts(ptr, system.slot_size(slot)));
+            }
+        }
+        let new_ptr = self.allocate(new_layout)?;
+        core::ptr::copy_nonoverlapping(
+            ptr.as_ptr(),
+            new_ptr.cast::<u8>().as_ptr(),
+            min(old_layout.size(), new_layout.size()),
+        );
+        self.deallocate(ptr, old_layout);
+        Ok(new_ptr)
+    }
+}
+
+/// kmalloc的最小分配大小为8字节，对齐保持不变
+#[inline]
+fn kmalloc_layout(layout: Layout) -> Layout {
+    if layout.size() < 8 {
+        Layout::from_size_align(8, layout.align()).unwrap()
+    } else {
+        layout
     }
 }
-pub struct SlabAllocator;
 
 unsafe impl GlobalAlloc for SlabAllocator {
+    #[track_caller]
     unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
         // ensure the size >= 8
-        let layout = if layout.size() < 8{
-            Layout::from_size_align(8,8).unwrap()
-        }else {
-            layout
-        };
-        let ptr = alloc_from_slab(layout);
-        match ptr {
-            Ok(ptr)=>ptr,
-            Err(err)=>panic!("{:?} {:?}",err,layout),
+        let layout = kmalloc_layout(layout);
+        self.try_alloc(layout).unwrap_or(core::ptr::null_mut())
+    }
+    #[track_caller]
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        let ptr = self.alloc(layout);
+        if !ptr.is_null() {
+            ptr.write_bytes(0, layout.size());
         }
+        ptr
     }
     unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
-        let layout = if layout.size() < 8{
-            Layout::from_size_align(8,8).unwrap()
-        }else {
-            layout
+        let layout = kmalloc_layout(layout);
+        self.try_dealloc(ptr, layout);
+    }
+    #[track_caller]
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let new_layout = Layout::from_size_align_unchecked(new_size, layout.align());
+        let Ok(_guard) = self.system().enter() else {
+            return core::ptr::null_mut();
         };
-        dealloc_to_slab(ptr,layout).unwrap();
+        if self.same_slot(layout, new_layout).is_some() {
+            return ptr;
+        }
+        let new_ptr = self.alloc(new_layout);
+        if !new_ptr.is_null() {
+            core::ptr::copy_nonoverlapping(ptr, new_ptr, min(layout.size(), new_size));
+            self.dealloc(ptr, layout);
+        }
+        new_ptr
     }
 }
 
 unsafe impl Allocator for SlabAllocator{
+    #[track_caller]
     fn allocate(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
         if layout.size() == 0 {
             return Ok(NonNull::slice_from_raw_parts(layout.dangling(), 0));
         }
-        let layout = if layout.size() < 8{
-            Layout::from_size_align(8,8).unwrap()
-        }else {
-            layout
-        };
-        match alloc_from_slab(layout) {
+        let layout = kmalloc_layout(layout);
+        let system = self.system();
+        let _guard = system.enter().map_err(|_| AllocError)?;
+        let slot = system.kmalloc_slot(layout, Zone::Normal).map_err(|_| AllocError)?;
+        match self.try_alloc(layout) {
             Ok(ptr) => {
                 let ptr = NonNull::new(ptr).ok_or(AllocError)?;
-                Ok(NonNull::slice_from_raw_parts(ptr, layout.size()))
+                // 返回slot的实际大小，调用者可以使用多余的部分
+                Ok(NonNull::slice_from_raw_parts(ptr, system.slot_size(slot)))
             }
             Err(_) => Err(AllocError),
         }
     }
+    #[track_caller]
+    fn allocate_zeroed(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+        let ptr = self.allocate(layout)?;
+        unsafe { ptr.cast::<u8>().as_ptr().write_bytes(0, ptr.len()) };
+        Ok(ptr)
+    }
     unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
         if layout.size() != 0 {
-            let layout = if layout.size() < 8{
-                Layout::from_size_align(8,8).unwrap()
-            }else {
-                layout
-            };
-            dealloc_to_slab(ptr.as_ptr(),layout).unwrap();
+            let layout = kmalloc_layout(layout);
+            self.try_dealloc(ptr.as_ptr(), layout);
+        }
+    }
+    #[track_caller]
+    unsafe fn grow(
+        &self,
+        ptr: NonNull<u8>,
+        old_layout: Layout,
+        new_layout: Layout,
+    ) -> Result<NonNull<[u8]>, AllocError> {
+        self.resize(ptr, old_layout, new_layout)
+    }
+    #[track_caller]
+    unsafe fn grow_zeroed(
+        &self,
+        ptr: NonNull<u8>,
+        old_layout: Layout,
+        new_layout: Layout,
+    ) -> Result<NonNull<[u8]>, AllocError> {
+        let new_ptr = self.resize(ptr, old_layout, new_layout)?;
+        // 原地增长时slot中剩余的部分可能残留旧数据，返回的整个slot都需要清零
+        new_ptr
+            .cast::<u8>()
+            .as_ptr()
+            .add(old_layout.size())
+            .write_bytes(0, new_ptr.len() - old_layout.size());
+        Ok(new_ptr)
+    }
+    #[track_caller]
+    unsafe fn shrink(
+        &self,
+        ptr: NonNull<u8>,
+        old_layout: Layout,
+        new_layout: Layout,
+    ) -> Result<NonNull<[u8]>, AllocError> {
+        self.resize(ptr, old_layout, new_layout)
+    }
+}
+
+#[cfg(test)]
+mod size_index_test {
+    use super::{SizeClass, SizeIndex, DEFAULT_SIZE_CLASSES};
+
+    fn check(classes: &[SizeClass]) {
+        let mut index = SizeIndex::new();
+        index.build(classes);
+        let max = classes[classes.len() - 1].size;
+        for size in (0..=max + 64).step_by(7).chain([1usize << 30, usize::MAX]) {
+            let expect = classes.iter().position(|class| class.size >= size);
+            assert_eq!(index.lookup(classes, size), expect, "size {}", size);
         }
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_default_classes() {
+        check(DEFAULT_SIZE_CLASSES);
+    }
+
+    #[test]
+    fn test_geometric_classes() {
+        static CLASSES: [SizeClass; 10] = [
+            SizeClass::new("c-8", 8),
+            SizeClass::new("c-96", 96),
+            SizeClass::new("c-192", 192),
+            SizeClass::new("c-5120", 5120),
+            SizeClass::new("c-6144", 6144),
+            SizeClass::new("c-7168", 7168),
+            SizeClass::new("c-8192", 8192),
+            SizeClass::new("c-10240", 10240),
+            SizeClass::new("c-12288", 12288),
+            SizeClass::new("c-40960", 40960),
+        ];
+        check(&CLASSES);
+    }
+}
@@ -12,61 +12,181 @@ mod formation;
 mod kmalloc;
 mod slab;
 
-use crate::formation::SlabError;
-use crate::slab::{create_mem_cache, MemCache, SlabInfo};
+pub use crate::formation::SlabError;
+use crate::slab::MemCache;
+use core::alloc::Layout;
 use core::marker::PhantomData;
 use doubly_linked_list::*;
 use preprint::pprintln;
 
-pub use crate::slab::{print_slab_system_info};
-pub use kmalloc::SlabAllocator;
+pub use crate::slab::{
+    AllocFlags, AllocTrack, CacheIssue, CacheOptions, CacheTunables, DebugFlags, Inconsistency,
+    LargeAllocInfo, LeakPolicy, LeakReport, LiveObject, RetentionPolicy, ShutdownReport, SlabInfo,
+    SlabSystem, ValidationReport, Zone, MIN_FRAME_SIZE, NOFAIL_RETRIES, VALIDATION_ISSUES_MAX,
+};
+pub use kmalloc::{
+    DeallocErrorHandler, KmallocConfig, OomHandler, SizeClass, SlabAllocator,
+    DEFAULT_SIZE_CLASSES, SIZE_CLASS_MAX,
+};
 
-/// Cache链表头
-static mut SLAB_CACHES: ListHead = ListHead::new();
-static mut MEM_CACHE_BOOT: MemCache = MemCache::new();
-
-/// 默认frame_size大小:0x1000 4k
-static mut FRAME_SIZE: usize = 0x1000;
-/// 默认cache_line_size大小:16
-static mut CACHE_LINE_SIZE: usize = 16;
+/// 全局的slab系统
+static mut SLAB_SYSTEM: SlabSystem = SlabSystem::new();
 
 #[inline]
-fn frame_size() -> usize {
-    unsafe { FRAME_SIZE }
+fn slab_system() -> &'static SlabSystem {
+    unsafe { &*core::ptr::addr_of!(SLAB_SYSTEM) }
 }
 
-#[inline]
-fn cls() -> usize {
-    unsafe { CACHE_LINE_SIZE }
+/// 页帧提供者
+///
+/// 用户需要向slab系统提供获取与释放frame的接口
+pub trait FrameProvider: Sync {
+    /// 分配num个连续的frame，失败时返回空指针
+    fn alloc_frames(&self, num: usize) -> *mut u8;
+    /// 释放num个连续的frame
+    fn free_frames(&self, addr: *mut u8, num: usize);
+    /// 从指定的内存区域分配num个连续的frame，默认不区分区域
+    fn alloc_frames_in(&self, num: usize, _zone: Zone) -> *mut u8 {
+        self.alloc_frames(num)
+    }
+    /// 释放从指定的内存区域分配的frame
+    fn free_frames_in(&self, addr: *mut u8, num: usize, _zone: Zone) {
+        self.free_frames(addr, num)
+    }
+}
+
+/// cpu拓扑信息
+///
+/// 对于多核cpu,需要用户提供一个获取当前cpu id的接口
+pub trait CpuTopology: Sync {
+    fn current_cpu_id(&self) -> usize;
 }
 
-extern "C" {
-    /// 用户需要向slab系统提供获取frame的接口
-    fn alloc_frames(num: usize) -> *mut u8;
-    /// 用户需要向slab系统提供释放frame的接口
-    fn free_frames(addr: *mut u8, num: usize);
-    /// 对于多核cpu,需要用户提供一个获取当前cpu id的接口
-    fn current_cpu_id() -> usize;
+#[cfg(feature = "extern-provider")]
+mod ffi {
+    extern "C" {
+        pub fn alloc_frames(num: usize) -> *mut u8;
+        pub fn free_frames(addr: *mut u8, num: usize);
+        pub fn current_cpu_id() -> usize;
+    }
 }
 
-/// 初始化slab系统，需要知道frame_size与cache_line_size
-pub fn init_slab_system(frame_size: usize, cache_line_size: usize) {
-    init_slab_info(frame_size, cache_line_size);
-    /// 初始化slab系统的两个基本cache
-    slab::mem_cache_init();
-    /// 初始化常用的Cache
-    kmalloc::init_kmalloc();
+/// 默认的页帧提供者
+///
+/// 使用由用户以`#[no_mangle]`导出的`alloc_frames`/`free_frames`/`current_cpu_id`符号
+#[cfg(feature = "extern-provider")]
+pub struct ExternProvider;
+
+#[cfg(feature = "extern-provider")]
+impl FrameProvider for ExternProvider {
+    fn alloc_frames(&self, num: usize) -> *mut u8 {
+        unsafe { ffi::alloc_frames(num) }
+    }
+    fn free_frames(&self, addr: *mut u8, num: usize) {
+        unsafe { ffi::free_frames(addr, num) }
+    }
 }
 
-/// 设置slab系统的基本信息
-#[inline]
-fn init_slab_info(frame_size: usize, cache_line_size: usize) {
-    unsafe {
-        FRAME_SIZE = frame_size;
-        CACHE_LINE_SIZE = cache_line_size;
+#[cfg(feature = "extern-provider")]
+impl CpuTopology for ExternProvider {
+    fn current_cpu_id(&self) -> usize {
+        unsafe { ffi::current_cpu_id() }
     }
 }
 
+/// 初始化全局的slab系统，需要知道frame_size、cache_line_size与cpu数量，
+/// 以及页帧提供者和cpu拓扑信息
+///
+/// 重复初始化会返回[`SlabError::AlreadyInitialized`]
+///
+/// # Safety
+///
+/// 见[`init_slab_system_with`]
+pub unsafe fn init_slab_system(
+    frame_size: usize,
+    cache_line_size: usize,
+    cpus: usize,
+    frame_provider: &'static dyn FrameProvider,
+    cpu_topology: &'static dyn CpuTopology,
+) -> Result<(), SlabError> {
+    init_slab_system_with(
+        frame_size,
+        cache_line_size,
+        cpus,
+        frame_provider,
+        cpu_topology,
+        KmallocConfig::default(),
+    )
+}
+
+/// 使用自定义的kmalloc配置初始化全局的slab系统
+///
+/// # Safety
+///
+/// 初始化需要独占全局的slab系统：在成功初始化的调用返回之前，其他cpu不能通过任何接口使用全局slab系统。
+/// 系统已经初始化时调用只会返回[`SlabError::AlreadyInitialized`]，不会修改系统，可以与其他操作并发。
+/// [`shutdown_slab_system`]之后重新初始化时，此前创建的[`SlabCache`]与分配的对象都不能再使用
+pub unsafe fn init_slab_system_with(
+    frame_size: usize,
+    cache_line_size: usize,
+    cpus: usize,
+    frame_provider: &'static dyn FrameProvider,
+    cpu_topology: &'static dyn CpuTopology,
+    config: KmallocConfig,
+) -> Result<(), SlabError> {
+    // 先通过共享引用检查参数并占用系统，重复初始化不会产生可变引用
+    slab_system().begin_init(frame_size, cache_line_size, cpus, &config)?;
+    // 全局的slab系统是静态变量，不会被移动
+    (*core::ptr::addr_of_mut!(SLAB_SYSTEM)).finish_init(
+        frame_size,
+        cache_line_size,
+        cpus,
+        frame_provider,
+        cpu_topology,
+        config,
+    )
+}
+
+/// 关闭全局的slab系统，把所有页帧还给页帧提供者
+///
+/// 会等待进行中的分配与释放结束，此后通过全局slab系统的操作都会返回NotInitialized
+pub fn shutdown_slab_system() -> Result<ShutdownReport, SlabError> {
+    slab_system().shutdown()
+}
+
+/// 按照分配标志从全局slab系统分配内存，使用kfree释放
+#[track_caller]
+pub fn kmalloc(layout: Layout, flags: AllocFlags) -> Result<*mut u8, SlabError> {
+    slab_system().kmalloc(layout, flags)
+}
+
+/// 从全局slab系统分配n个大小为size的元素组成的数组，总大小溢出时返回错误
+#[track_caller]
+pub fn kmalloc_array(
+    n: usize,
+    size: usize,
+    align: usize,
+    flags: AllocFlags,
+) -> Result<*mut u8, SlabError> {
+    slab_system().kmalloc_array(n, size, align, flags)
+}
+
+/// 从全局slab系统分配清零的数组
+#[track_caller]
+pub fn kcalloc(n: usize, size: usize, align: usize, flags: AllocFlags) -> Result<*mut u8, SlabError> {
+    slab_system().kcalloc(n, size, align, flags)
+}
+
+/// 只根据地址释放全局slab系统分配的内存，不需要原来的Layout
+pub fn kfree(ptr: *mut u8) -> Result<(), SlabError> {
+    slab_system().kfree(ptr)
+}
+
+/// 打印全局slab系统内的所有cache 信息
+pub fn print_slab_system_info() {
+    slab_system().print_info();
+}
+
 /// 自定义对象
 ///
 /// 其需要实现一个构造函数，用于初始化分配的内存
@@ -80,46 +200,117 @@ pub trait ObjectAllocator<T: Object> {
     fn alloc(&self) -> Result<&mut T,SlabError>;
     /// 释放一个对象，如果释放失败则返回失败原因
     fn dealloc(&self, obj: &mut T) -> Result<(), SlabError>;
-    /// 销毁对象分配器
-    fn destroy(&mut self);
+    /// 销毁对象分配器，返回仍未释放的对象的统计
+    fn destroy(&mut self) -> Result<LeakReport, SlabError>;
 }
 
 pub struct SlabCache<T: Object> {
     cache: &'static mut MemCache,
+    /// cache所在的slab系统，系统关闭后cache对象已经被回收，需要先检查系统的状态
+    system: &'static SlabSystem,
     obj_type: PhantomData<T>,
 }
 
 impl<T: Object> SlabCache<T> {
+    /// 在全局的slab系统中创建cache
     pub fn new(name: &'static str) -> Result<SlabCache<T>, SlabError> {
+        Self::new_in(name, slab_system())
+    }
+    /// 在指定的slab系统中创建cache
+    pub fn new_in(name: &'static str, system: &'static SlabSystem) -> Result<SlabCache<T>, SlabError> {
+        Self::with_options(name, system, CacheOptions::default())
+    }
+    /// 在指定的slab系统中使用给定的参数创建cache
+    pub fn with_options(
+        name: &'static str,
+        system: &'static SlabSystem,
+        options: CacheOptions,
+    ) -> Result<SlabCache<T>, SlabError> {
         let size = core::mem::size_of::<T>() as u32;
         let align = core::mem::align_of::<T>() as u32;
-        let cache = create_mem_cache(name, size, align)?;
+        let _guard = system.enter()?;
+        let cache = system.create_mem_cache(name, size, align, options)?;
         Ok(SlabCache {
             cache,
+            system,
             obj_type: PhantomData,
         })
     }
+    /// slab系统关闭后不再打印
     pub fn print_info(&self) {
-        self.cache.print_info();
+        if let Ok(_guard) = self.system.enter() {
+            self.cache.print_info();
+        }
     }
+    /// slab系统关闭后调用会panic
     pub fn get_cache_info(&self)->SlabInfo{
+        let _guard = self.system.enter().expect("slab system has been shut down");
         self.cache.get_cache_info()
     }
-}
-
-impl<T: Object> ObjectAllocator<T> for SlabCache<T> {
-    fn alloc(&self) -> Result<&mut T,SlabError> {
-        let obj_ptr = self.cache.alloc()?;
+    /// 检查cache的slab链表与高速缓存是否一致，slab系统关闭后调用会panic
+    pub fn validate(&self) -> ValidationReport {
+        let _guard = self.system.enter().expect("slab system has been shut down");
+        self.cache.validate()
+    }
+    /// 调整本地高速缓存参数
+    pub fn set_tunables(&self, tunables: CacheTunables) -> Result<(), SlabError> {
+        let _guard = self.system.enter()?;
+        self.cache.set_tunables(tunables)
+    }
+    /// 按照分配标志分配一个对象
+    ///
+    /// ZERO在构造对象之前将内存清零
+    #[track_caller]
+    pub fn alloc_with(&self, flags: AllocFlags) -> Result<&mut T, SlabError> {
+        let _guard = self.system.enter()?;
+        let obj_ptr = self.cache.alloc_with(flags)?;
         unsafe {
             let obj = obj_ptr as *mut T;
             obj.write(T::construct());
             Ok(&mut *obj)
         }
     }
+    /// 从cache所在的slab系统中分配n个连续的对象，使用dealloc_array释放
+    ///
+    /// 数组由kmalloc提供，不占用cache中的对象，总大小溢出时返回SizeTooLarge
+    #[track_caller]
+    pub fn alloc_array(&self, n: usize, flags: AllocFlags) -> Result<&mut [T], SlabError> {
+        let layout = Layout::array::<T>(n).map_err(|_| SlabError::SizeTooLarge)?;
+        let ptr = self.system.kmalloc(layout, flags)? as *mut T;
+        unsafe {
+            for i in 0..n {
+                ptr.add(i).write(T::construct());
+            }
+            Ok(core::slice::from_raw_parts_mut(ptr, n))
+        }
+    }
+    /// 查询对象的分配者，cache没有开启STORE_USER时返回None
+    pub fn alloc_track(&self, obj: &T) -> Option<AllocTrack> {
+        let _guard = self.system.enter().ok()?;
+        self.cache.alloc_track(obj as *const T as *mut u8)
+    }
+    /// 销毁cache，每个仍未释放的对象都会调用一次on_live
+    pub fn destroy_with(&mut self, on_live: impl FnMut(LiveObject)) -> Result<LeakReport, SlabError> {
+        let _guard = self.system.enter()?;
+        self.cache.destroy_with(on_live)
+    }
+    /// 释放alloc_array分配的数组
+    pub fn dealloc_array(&self, objs: &mut [T]) -> Result<(), SlabError> {
+        self.system.kfree(objs.as_mut_ptr() as *mut u8)
+    }
+}
+
+impl<T: Object> ObjectAllocator<T> for SlabCache<T> {
+    #[track_caller]
+    fn alloc(&self) -> Result<&mut T,SlabError> {
+        self.alloc_with(AllocFlags::empty())
+    }
     fn dealloc(&self, obj: &mut T) -> Result<(), SlabError> {
+        let _guard = self.system.enter()?;
         self.cache.dealloc(obj as *mut T as *mut u8)
     }
-    fn destroy(&mut self) {
-        self.cache.destroy();
+    fn destroy(&mut self) -> Result<LeakReport, SlabError> {
+        let _guard = self.system.enter()?;
+        self.cache.destroy()
     }
 }
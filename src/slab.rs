@@ -1,35 +1,48 @@
-use super::alloc_frames;
 use crate::formation::*;
-use crate::{cls, frame_size, free_frames, MEM_CACHE_BOOT};
-use crate::{current_cpu_id, SLAB_CACHES};
+use crate::kmalloc::{self, KmallocConfig, SizeClass, SizeIndex, SIZE_CLASS_MAX};
+use crate::{CpuTopology, FrameProvider};
 use alloc::alloc::dealloc;
 use core::alloc::Layout;
 use bitflags::bitflags;
 use core::cmp::{max, min};
 use core::fmt::{Debug, Formatter, Write};
 use core::mem::forget;
+use core::marker::PhantomPinned;
 use core::ops::Add;
-use core::sync::atomic::AtomicUsize;
+use core::panic::Location;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicBool, AtomicU8, AtomicUsize, Ordering};
 use doubly_linked_list::*;
 use preprint::pprintln;
 use spin::mutex::SpinMutex;
 use spin::rwlock::RwLock;
 use spin::Mutex;
 
-/// 高速缓存的limit
+/// 高速缓存默认的limit
 const PER_CPU_OBJECTS: usize = 16;
-/// 支持8个核
-const CPUS: usize = 8;
-/// 空闲链表的上限，达到上线将触发回收页面
+/// 空闲slab默认最多占用的页帧数量，达到上限将触发回收页面
 const FREE_LIST_MAX:usize = 16;
 
-const VAL: ArrayCache = ArrayCache::new();
-static mut ARRAY_CACHE_FOR_BOOT: [ArrayCache; CPUS] = [VAL; CPUS];
-static mut ARRAY_CACHE_FOR_ARRAY: [ArrayCache; CPUS] = [VAL; CPUS];
-static mut ARRAY_CACHE_NODE_BOOT: ArrayCache = ArrayCache::new();
-static mut ARRAY_CACHE_NODE_ARRAY: ArrayCache = ArrayCache::new();
-
-
+/// 默认frame_size大小:0x1000 4k
+const FRAME_SIZE: usize = 0x1000;
+/// frame_size的下限
+///
+/// 对象大小达到frame_size的1/8时slab描述符放在外部，由kmalloc分配。
+/// 更小的frame_size会使小对象的kmalloc cache也需要外部描述符，增长时又要从自身分配
+pub const MIN_FRAME_SIZE: usize = 1024;
+/// 默认cache_line_size大小:16
+const CACHE_LINE_SIZE: usize = 16;
+
+/// red zone的大小
+const RED_ZONE_SIZE: u32 = core::mem::size_of::<u64>() as u32;
+/// red zone中填充的内容
+const RED_ZONE_PATTERN: u64 = 0xbbbb_bbbb_bbbb_bbbb;
+/// 空闲对象中填充的内容
+const POISON_FREE: u8 = 0x6b;
+/// 空闲对象的最后一个字节
+const POISON_END: u8 = 0xa5;
+/// 刚分配的对象中填充的内容
+const POISON_INUSE: u8 = 0x5a;
 
 bitflags! {
     pub struct Flags:u8{
@@ -39,6 +52,303 @@ bitflags! {
     }
 }
 
+bitflags! {
+    /// 分配标志，对应linux中的gfp_t
+    pub struct AllocFlags: u32 {
+        /// 不能睡眠或申请页帧，只从本地/共享高速缓存和已有的slab中分配
+        const ATOMIC = 0b0000_0001;
+        /// 分配的内存清零
+        const ZERO = 0b0000_0010;
+        /// 页帧不足时回收空闲slab后重试，最多重试[`NOFAIL_RETRIES`]次
+        const NOFAIL = 0b0000_0100;
+        /// 从DMA区域分配
+        const DMA = 0b0000_1000;
+        /// 从4G以下的DMA32区域分配
+        const DMA32 = 0b0001_0000;
+    }
+}
+
+impl AllocFlags {
+    /// 标志中选择的内存区域，同时指定时取更严格的DMA
+    pub fn zone(&self) -> Zone {
+        if self.contains(AllocFlags::DMA) {
+            Zone::Dma
+        } else if self.contains(AllocFlags::DMA32) {
+            Zone::Dma32
+        } else {
+            Zone::Normal
+        }
+    }
+    fn check(&self) -> Result<(), SlabError> {
+        if self.contains(AllocFlags::ATOMIC | AllocFlags::NOFAIL) {
+            return Err(SlabError::InvalidFlags);
+        }
+        Ok(())
+    }
+}
+
+bitflags! {
+    /// cache的调试选项
+    pub struct DebugFlags: u32 {
+        /// 释放时检查指针是否属于此cache并且位于对象的起始位置
+        const CONSISTENCY_CHECKS = 0b0000_0001;
+        /// 在每个slab的位图中记录对象是否已经分配，检测重复释放
+        const DOUBLE_FREE = 0b0000_0010;
+        /// 在对象前后放置red zone，释放时检查是否被改写，被改写时恢复并照常释放对象
+        const RED_ZONE = 0b0000_0100;
+        /// 空闲对象填充固定的内容，分配时检查是否被改写，分配后填充另一种内容
+        ///
+        /// 被改写的对象只会报告，分配仍然成功
+        const POISON = 0b0000_1000;
+        /// 记录每个已分配对象的分配位置
+        const STORE_USER = 0b0001_0000;
+    }
+}
+
+/// 对象的分配者信息
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AllocTrack {
+    /// 调用分配接口的位置
+    pub caller: &'static Location<'static>,
+    /// 分配时由[`SlabSystem::set_track_context`]设置的函数得到的上下文，如任务id
+    pub context: usize,
+}
+
+impl Default for DebugFlags {
+    fn default() -> Self {
+        DebugFlags::empty()
+    }
+}
+
+/// 页帧所在的内存区域，由页帧提供者解释
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Zone {
+    #[default]
+    Normal,
+    Dma,
+    Dma32,
+}
+
+/// 内存区域的数量
+const ZONE_COUNT: usize = 3;
+
+impl Zone {
+    pub(crate) const ALL: [Zone; ZONE_COUNT] = [Zone::Normal, Zone::Dma, Zone::Dma32];
+
+    #[inline]
+    const fn index(self) -> usize {
+        self as usize
+    }
+    /// 该区域的kmalloc cache名称前缀
+    const fn prefix(self) -> &'static str {
+        match self {
+            Zone::Normal => "",
+            Zone::Dma => "dma-",
+            Zone::Dma32 => "dma32-",
+        }
+    }
+}
+
+/// 本地高速缓存参数，对应linux slabinfo中的tunables
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CacheTunables {
+    /// 每个cpu的本地高速缓存可以拥有的最大对象数量
+    pub limit: u32,
+    /// 每次从共享高速缓存或者slab中获取/归还的对象数量
+    pub batch_count: u32,
+    /// 共享高速缓存可以拥有的最大对象数量，小于batch_count时不使用共享高速缓存
+    pub shared: u32,
+}
+
+impl Default for CacheTunables {
+    fn default() -> Self {
+        Self {
+            limit: PER_CPU_OBJECTS as u32,
+            batch_count: PER_CPU_OBJECTS as u32 / 2,
+            shared: PER_CPU_OBJECTS as u32,
+        }
+    }
+}
+
+impl CacheTunables {
+    fn check(&self) -> Result<(), SlabError> {
+        if self.limit == 0 || self.batch_count == 0 || self.batch_count > self.limit {
+            return Err(SlabError::InvalidTunables);
+        }
+        Ok(())
+    }
+    /// 所有本地高速缓存与共享高速缓存需要的对象数量
+    fn entries(&self, cpus: usize) -> Result<usize, SlabError> {
+        cpus.checked_mul(self.limit as usize)
+            .and_then(|n| n.checked_add(self.shared as usize))
+            .ok_or(SlabError::InvalidTunables)
+    }
+}
+
+/// 空闲slab的保留策略
+///
+/// 空闲slab占用的内存超过上限时会把多余的slab还给页帧提供者
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RetentionPolicy {
+    /// 最多保留的页帧数量
+    Frames(usize),
+    /// 最多保留的字节数
+    Bytes(usize),
+}
+
+impl Default for RetentionPolicy {
+    fn default() -> Self {
+        RetentionPolicy::Frames(FREE_LIST_MAX)
+    }
+}
+
+impl RetentionPolicy {
+    /// 换算成页帧数量
+    fn frames(&self, frame_size: usize) -> usize {
+        match *self {
+            RetentionPolicy::Frames(frames) => frames,
+            RetentionPolicy::Bytes(bytes) => bytes / frame_size,
+        }
+    }
+}
+
+/// 销毁仍有对象未释放的cache时的处理方式
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum LeakPolicy {
+    /// 报告未释放的对象后继续销毁
+    #[default]
+    Warn,
+    /// 报告未释放的对象并拒绝销毁，cache仍然可以使用
+    Refuse,
+}
+
+/// 销毁cache时仍未释放的对象
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LiveObject {
+    /// 对象地址
+    pub addr: usize,
+    /// 对象所在slab的起始地址
+    pub slab: usize,
+    /// 开启STORE_USER时对象的分配者
+    pub track: Option<AllocTrack>,
+}
+
+/// 销毁cache时的泄漏统计
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct LeakReport {
+    /// 未释放的对象数量
+    pub live_objects: usize,
+    /// 包含未释放对象的slab数量
+    pub live_slabs: usize,
+}
+
+/// 一次一致性检查最多记录的不一致数量
+pub const VALIDATION_ISSUES_MAX: usize = 32;
+
+/// NOFAIL分配在页帧不足时最多重试的次数
+///
+/// 页帧一直不足时无限重试会让分配者始终占用cpu，超过次数后仍然返回CantAllocFrame
+pub const NOFAIL_RETRIES: usize = 16;
+
+/// 一致性检查发现的问题，cpu为None时表示共享高速缓存
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Inconsistency {
+    /// slab不属于此cache，slab为描述符的地址
+    ForeignSlab { slab: usize },
+    /// partial链表中的slab已满或为空
+    PartialSlabState { slab: usize, used: u32 },
+    /// full链表中的slab未满
+    FullSlabNotFull { slab: usize, used: u32 },
+    /// free链表中的slab仍有对象被分配
+    FreeSlabInUse { slab: usize, used: u32 },
+    /// used_object与free_list中已分配的数量不一致
+    UsedCountMismatch { slab: usize, used: u32, next_free: u32 },
+    /// free_list中的空闲下标越界
+    FreeIndexOutOfRange { slab: usize, index: u32 },
+    /// free_list中的空闲下标重复
+    DuplicateFreeIndex { slab: usize, index: u32 },
+    /// free_list_len与free链表的长度不一致
+    FreeListLen { recorded: u32, actual: usize },
+    /// 高速缓存中的对象数量超过上限
+    CacheOverflow { cpu: Option<usize>, avail: u32, limit: u32 },
+    /// 高速缓存中的指针不是此cache的对象
+    ForeignCachedObject { cpu: Option<usize>, addr: usize },
+    /// 高速缓存中的对象在slab中处于空闲状态
+    CachedObjectFree { cpu: Option<usize>, addr: usize },
+    /// 对象前后的red zone被改写
+    RedZoneCorrupted { slab: usize, index: usize },
+}
+
+/// 发现问题的cache以及问题
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CacheIssue {
+    pub cache: &'static str,
+    pub issue: Inconsistency,
+}
+
+/// 一致性检查的结果
+///
+/// 只保留前[`VALIDATION_ISSUES_MAX`]个问题，count为发现的总数
+#[derive(Debug, Clone, Copy)]
+pub struct ValidationReport {
+    issues: [Option<CacheIssue>; VALIDATION_ISSUES_MAX],
+    count: usize,
+}
+
+impl Default for ValidationReport {
+    fn default() -> Self {
+        Self {
+            issues: [None; VALIDATION_ISSUES_MAX],
+            count: 0,
+        }
+    }
+}
+
+impl ValidationReport {
+    /// 是否没有发现问题
+    pub fn is_ok(&self) -> bool {
+        self.count == 0
+    }
+    /// 发现的问题总数
+    pub fn count(&self) -> usize {
+        self.count
+    }
+    /// 记录下来的问题
+    pub fn iter(&self) -> impl Iterator<Item = &CacheIssue> {
+        self.issues.iter().flatten()
+    }
+    fn push(&mut self, cache: &'static str, issue: Inconsistency) {
+        if let Some(slot) = self.issues.get_mut(self.count) {
+            *slot = Some(CacheIssue { cache, issue });
+        }
+        self.count += 1;
+    }
+}
+
+/// 创建cache时的可选参数
+#[derive(Debug, Clone, Copy, Default)]
+pub struct CacheOptions {
+    /// 本地高速缓存参数
+    pub tunables: CacheTunables,
+    /// 空闲slab的保留策略
+    pub retention: RetentionPolicy,
+    /// slab页帧所在的内存区域
+    pub zone: Zone,
+    /// 调试选项
+    pub debug: DebugFlags,
+    /// 销毁时仍有对象未释放的处理方式
+    pub leak_policy: LeakPolicy,
+}
+
+/// 关闭slab系统时的泄漏统计
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct ShutdownReport {
+    /// 仍有对象未释放的cache数量
+    pub leaked_caches: usize,
+    /// 未释放的对象数量
+    pub leaked_objects: usize,
+}
+
 pub struct SlabInfo {
     pub cache_name: &'static str,
     pub object_size: u32,
@@ -49,14 +359,38 @@ pub struct SlabInfo {
     pub used_objects: u32,
     pub limit: u32,
     pub batch_count: u32,
+    pub shared_limit: u32,
     pub local_objects: u32,
     pub shared_objects: u32,
+    pub retention: RetentionPolicy,
+    /// slab页帧所在的内存区域
+    pub zone: Zone,
+    /// 空闲slab占用的页帧数量
+    pub free_frames: u32,
+}
+
+/// 本地高速缓存参数以及对应的对象数组
+#[derive(Debug)]
+struct ArrayEntries {
+    tunables: CacheTunables,
+    /// 所有本地高速缓存与共享高速缓存的对象数组
+    entries: *mut usize,
 }
 
 #[derive(Debug)]
 pub struct MemCache {
-    /// 本地高速缓存
-    array_cache: [*mut ArrayCache; CPUS as usize],
+    /// 本地高速缓存，每个cpu一个，共享高速缓存紧随其后
+    array_cache: *mut ArrayCache,
+    /// 本地高速缓存参数与对象数组，调整参数时需要持有此锁
+    array_entries: Mutex<ArrayEntries>,
+    /// 空闲slab的保留策略
+    retention: RetentionPolicy,
+    /// slab页帧所在的内存区域
+    zone: Zone,
+    /// 调试选项
+    debug: DebugFlags,
+    /// 销毁时仍有对象未释放的处理方式
+    leak_policy: LeakPolicy,
     list: ListHead,
     /// 每个slab的对象数量
     per_objects: u32,
@@ -66,6 +400,10 @@ pub struct MemCache {
     align: u32,
     /// 对象大小
     object_size: u32,
+    /// 每个对象在slab中占用的空间，包括red zone
+    stride: u32,
+    /// 对象在其占用的空间中的偏移，即前面red zone的大小
+    obj_offset: u32,
     /// 可着色数量
     color: u32,
     /// 着色偏移==缓存行大小
@@ -78,6 +416,10 @@ pub struct MemCache {
     cache_name: &'static str,
     /// 控制信息
     flags: Flags,
+    /// 所属的slab系统
+    system: *const SlabSystem,
+    /// 被slab系统用作元数据的对象数量
+    meta_objects: AtomicUsize,
 }
 unsafe impl Sync for MemCache {}
 unsafe impl Send for MemCache {}
@@ -114,6 +456,12 @@ macro_rules! slab_layout {
         Layout::from_size_align(core::mem::size_of::<Slab>(), core::mem::align_of::<Slab>()).unwrap()
     };
 }
+/// SLAB_OFF时在外部分配的free_list数组以及调试信息的大小
+macro_rules! free_list_size {
+    ($cache:expr) => {
+        $cache.slab_meta_size($cache.per_objects) as usize
+    };
+}
 macro_rules! array_cache_layout {
     ()=> {
         Layout::from_size_align(core::mem::size_of::<ArrayCache>(), core::mem::align_of::<ArrayCache>()).unwrap()
@@ -126,25 +474,45 @@ macro_rules! array_cache_layout {
 impl MemCache {
     pub const fn new() -> Self {
         Self {
-            array_cache: [core::ptr::null_mut(); CPUS as usize],
+            array_cache: core::ptr::null_mut(),
+            array_entries: Mutex::new(ArrayEntries {
+                tunables: CacheTunables {
+                    limit: PER_CPU_OBJECTS as u32,
+                    batch_count: PER_CPU_OBJECTS as u32 / 2,
+                    shared: PER_CPU_OBJECTS as u32,
+                },
+                entries: core::ptr::null_mut(),
+            }),
+            retention: RetentionPolicy::Frames(FREE_LIST_MAX),
+            zone: Zone::Normal,
+            debug: DebugFlags::empty(),
+            leak_policy: LeakPolicy::Warn,
             list: ListHead::new(),
             per_objects: 0,
             per_frames: 0,
             align: 0,
             object_size: 0,
+            stride: 0,
+            obj_offset: 0,
             color: 0,
             color_off: 0,
             color_next: 0,
             mem_cache_node: CacheNode::new(),
             cache_name: "",
             flags: Flags::empty(),
+            system: core::ptr::null(),
+            meta_objects: AtomicUsize::new(0),
         }
     }
+    #[inline]
+    pub(crate) fn system(&self) -> &'static SlabSystem {
+        unsafe { &*self.system }
+    }
     /// 打印信息
     pub fn print_info(&self) {
         let slab_info = self.get_cache_info();
         pprintln!(
-            "{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}",
+            "{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}",
             self.cache_name,
             self.object_size,
             self.align,
@@ -152,10 +520,12 @@ impl MemCache {
             self.per_objects,
             slab_info.total_objects,
             slab_info.used_objects,
-            PER_CPU_OBJECTS,
-            PER_CPU_OBJECTS / 2,
+            slab_info.limit,
+            slab_info.batch_count,
+            slab_info.shared_limit,
             slab_info.local_objects,
-            slab_info.shared_objects
+            slab_info.shared_objects,
+            slab_info.free_frames
         );
     }
     pub fn get_cache_info(&self) -> SlabInfo {
@@ -165,12 +535,13 @@ impl MemCache {
         let used = self.mem_cache_node.used_objects(per_objects);
         // 计算本地高速缓存的对象数量
         let mut local = 0;
-        for i in 0..CPUS {
-            local += unsafe { (*self.array_cache[i]).inner.lock().avail };
+        for i in 0..self.system().cpus {
+            local += unsafe { (*self.array_cache.add(i)).inner.lock().avail };
         }
         //计算共享高速缓存的对象数量
         let shared = unsafe { (*self.mem_cache_node.shared).inner.lock().avail };
         assert!(used as u32 >= local + shared);
+        let tunables = self.array_entries.lock().tunables;
         SlabInfo {
             cache_name: self.cache_name,
             object_size: self.object_size,
@@ -179,10 +550,14 @@ impl MemCache {
             per_objects: self.per_objects,
             total_objects: total as u32,
             used_objects: used as u32 - shared - local,
-            limit: PER_CPU_OBJECTS as u32,
-            batch_count: PER_CPU_OBJECTS as u32 / 2,
+            limit: tunables.limit,
+            batch_count: tunables.batch_count,
+            shared_limit: tunables.shared,
             local_objects: local,
             shared_objects: shared,
+            retention: self.retention,
+            zone: self.zone,
+            free_frames: *self.mem_cache_node.free_list_len.read() << self.per_frames,
         }
     }
 
@@ -190,20 +565,21 @@ impl MemCache {
     /// 需要的页面数量，确保内部碎片 < 12.5%
     /// 再计算每个slab中对象的数量
     fn init_cache_object_num(&mut self) {
+        let frame_size = self.system().frame_size;
         let mut order = 0;
         let mut left_over = 0;
         loop {
-            let total_size = frame_size() * (1 << order);
+            let total_size = frame_size * (1 << order);
             let object_num = if self.flags == Flags::SLAB_OFF {
                 // slab描述符和freelist数组在外部
-                total_size / self.object_size as usize
+                total_size / self.stride as usize
             } else {
                 // slab描述符和freelist数组在内部
                 let mut object_num = (total_size - core::mem::size_of::<Slab>())
-                    / (self.object_size as usize + core::mem::size_of::<u32>());
+                    / (self.stride as usize + core::mem::size_of::<u32>());
                 // 计算对齐后slab描述符的大小
-                while let slab_align = slab_descriptor_align_size(object_num as u32, self.align) {
-                    if (slab_align + object_num as u32 * self.object_size) < total_size as u32 {
+                while let slab_align = self.slab_descriptor_align_size(object_num as u32) {
+                    if (slab_align + object_num as u32 * self.stride) < total_size as u32 {
                         break;
                     }
                     object_num -= 1;
@@ -211,15 +587,15 @@ impl MemCache {
                 object_num
             };
             //检查内部碎片的比例
-            left_over = total_size - object_num * self.object_size as usize;
+            left_over = total_size - object_num * self.stride as usize;
             if self.flags == Flags::SLAB_ON {
-                left_over -= slab_descriptor_align_size(object_num as u32, self.align) as usize;
+                left_over -= self.slab_descriptor_align_size(object_num as u32) as usize;
             }
             if left_over * 8 < total_size {
                 self.per_objects = object_num as u32;
                 self.per_frames = order;
                 //初始化可着色的数量
-                self.color = (left_over / cls()) as u32;
+                self.color = (left_over / self.color_off as usize) as u32;
                 break;
             } // 找到页帧正确的数量
             order += 1;
@@ -227,36 +603,168 @@ impl MemCache {
         trace!(
             "left_over is {}, total_size is {}",
             left_over,
-            frame_size() * (1 << self.per_frames)
+            frame_size * (1 << self.per_frames)
         );
     }
 
+    /// 计算每个对象在slab中占用的空间，再计算slab的大小
+    fn init_object_layout(&mut self) {
+        if self.debug.contains(DebugFlags::RED_ZONE) {
+            // 对象前后各有一个red zone，前面的red zone扩展到align以保持对象的对齐
+            self.obj_offset = max(RED_ZONE_SIZE, self.align);
+            self.stride = align_to!(self.obj_offset + self.object_size + RED_ZONE_SIZE, self.align);
+        } else {
+            self.obj_offset = 0;
+            self.stride = self.object_size;
+        }
+        self.init_cache_object_num();
+    }
+
+    /// slab中n个对象的管理数据依次为free_list数组、分配者记录与分配状态位图
+    ///
+    /// 返回分配者记录与位图相对free_list的偏移以及总大小
+    fn slab_meta_layout(&self, n: u32) -> (usize, usize, usize) {
+        let mut size = n as usize * core::mem::size_of::<u32>();
+        let track = align_to!(size, core::mem::align_of::<Option<AllocTrack>>());
+        if self.debug.contains(DebugFlags::STORE_USER) {
+            size = track + n as usize * core::mem::size_of::<Option<AllocTrack>>();
+        }
+        let map = size;
+        if self.debug.contains(DebugFlags::DOUBLE_FREE) {
+            size += (n as usize).div_ceil(8);
+        }
+        (track, map, size)
+    }
+
+    /// slab中n个对象的free_list数组以及调试信息的大小
+    fn slab_meta_size(&self, n: u32) -> u32 {
+        self.slab_meta_layout(n).2 as u32
+    }
+
+    /// 对齐后的slab描述符大小，包括free_list数组以及调试信息
+    #[inline]
+    fn slab_descriptor_align_size(&self, object_num: u32) -> u32 {
+        align_to!(
+            self.slab_meta_size(object_num) + core::mem::size_of::<Slab>() as u32,
+            self.align
+        )
+    }
+
     /// 在使用init初始化cache后需要使用此函数完成array_cache的初始化
     /// 对于系统初始化阶段的两个初始cache不经过这里
-    fn set_array_cache(&mut self) -> Result<(), SlabError> {
-        //从array_cache中分配得到
-        for i in 0..CPUS {
-            let array_cache_addr = get_array_cache()?;
-            self.array_cache[i] = array_cache_addr as *mut ArrayCache;
-            unsafe { (*self.array_cache[i]).inner.lock().init() };
-        }
-        self.mem_cache_node.set_array_cache()?;
+    fn set_array_cache(&mut self, tunables: CacheTunables) -> Result<(), SlabError> {
+        //从array_cache中分配得到cpus + 1个本地高速缓存
+        let array_cache_addr = self.system().get_array_cache()?;
+        self.set_array_cache_from(array_cache_addr as *mut ArrayCache, tunables)
+            .inspect_err(|_| {
+                // 对象数组分配失败，本地高速缓存还给array_cache
+                let _ = self.system().array_cache().dealloc(array_cache_addr);
+            })
+    }
+
+    /// 使用给定的cpus + 1个本地高速缓存，最后一个作为共享高速缓存
+    fn set_array_cache_from(
+        &mut self,
+        array_cache: *mut ArrayCache,
+        tunables: CacheTunables,
+    ) -> Result<(), SlabError> {
+        let cpus = self.system().cpus;
+        for i in 0..=cpus {
+            unsafe { array_cache.add(i).write(ArrayCache::new()) };
+        }
+        self.array_cache = array_cache;
+        self.mem_cache_node.shared = unsafe { array_cache.add(cpus) };
+        self.array_entries.get_mut().entries = core::ptr::null_mut();
+        self.set_tunables(tunables)
+    }
+
+    /// 调整本地高速缓存参数
+    ///
+    /// 会重新分配对象数组，原来缓存在本地高速缓存与共享高速缓存中的对象会先还给slab，
+    /// 每个高速缓存都在持有自己的锁时清空并切换到新的数组
+    pub fn set_tunables(&self, tunables: CacheTunables) -> Result<(), SlabError> {
+        tunables.check()?;
+        let system = self.system();
+        let cpus = system.cpus;
+        let entries_size = tunables.entries(cpus)? * core::mem::size_of::<usize>();
+        // 同一时间只能有一个调整者
+        let mut array_entries = self.array_entries.lock();
+        let entries = system.alloc_meta(entries_size)? as *mut usize;
+        let limit = tunables.limit as usize;
+        let shared = unsafe { &*self.mem_cache_node.shared };
+        for i in 0..cpus {
+            let mut array_cache = unsafe { &*self.array_cache.add(i) }.inner.lock();
+            let _shared = shared.inner.lock();
+            self.mem_cache_node.drain(&mut array_cache);
+            array_cache.init(tunables.limit, tunables.batch_count, unsafe { entries.add(i * limit) });
+        }
+        let mut shared_array = shared.inner.lock();
+        self.mem_cache_node.drain(&mut shared_array);
+        shared_array.init(tunables.shared, tunables.batch_count, unsafe { entries.add(cpus * limit) });
+        drop(shared_array);
+        // 释放旧的对象数组
+        if !array_entries.entries.is_null() {
+            let old_size = array_entries.tunables.entries(cpus).unwrap() * core::mem::size_of::<usize>();
+            system.free_meta(array_entries.entries as *mut u8, old_size);
+        }
+        array_entries.entries = entries;
+        array_entries.tunables = tunables;
         Ok(())
     }
 
-    fn init(&mut self, name: &'static str, object_size: u32, align: u32) -> Result<(), SlabError> {
-        self.array_cache = [core::ptr::null_mut(); CPUS];
+    /// 把本地高速缓存与共享高速缓存中的对象全部还给slab
+    fn drain_all(&self) {
+        let shared = unsafe { &*self.mem_cache_node.shared };
+        for i in 0..self.system().cpus {
+            let mut array_cache = unsafe { &*self.array_cache.add(i) }.inner.lock();
+            let _shared = shared.inner.lock();
+            self.mem_cache_node.drain(&mut array_cache);
+        }
+        self.mem_cache_node.drain(&mut shared.inner.lock());
+    }
+
+    /// 当前cpu的本地高速缓存
+    fn local_array_cache(&self) -> Result<&ArrayCache, SlabError> {
+        let system = self.system();
+        let cpu_id = system.current_cpu_id();
+        if cpu_id >= system.cpus {
+            return Err(SlabError::InvalidCpuId);
+        }
+        Ok(unsafe { &*self.array_cache.add(cpu_id) })
+    }
+
+    fn init(
+        &mut self,
+        system: &SlabSystem,
+        name: &'static str,
+        object_size: u32,
+        align: u32,
+    ) -> Result<(), SlabError> {
+        self.system = system as *const SlabSystem;
+        self.meta_objects = AtomicUsize::new(0);
+        self.zone = Zone::Normal;
+        self.debug = DebugFlags::empty();
+        self.leak_policy = LeakPolicy::Warn;
+        self.array_cache = core::ptr::null_mut();
+        // cache对象所在的内存没有初始化过，锁需要重新创建
+        self.array_entries = Mutex::new(ArrayEntries {
+            tunables: CacheTunables::default(),
+            entries: core::ptr::null_mut(),
+        });
         self.mem_cache_node.init();
         self.cache_name = name;
-        self.color_off = cls() as u32; //cache行大小
+        self.color_off = system.cache_line_size as u32; //cache行大小
+        self.color_next = 0;
         self.align = if align.is_power_of_two() && align != 0 {
             max(align, 8)
         } else {
             core::mem::size_of::<usize>() as u32
         };
+        // 着色偏移不能破坏对象的对齐
+        self.color_off = max(self.color_off, self.align);
         // 对象大小对齐到align
         self.object_size = align_to!(object_size, self.align);
-        self.flags = if object_size * 8 >= frame_size() as u32 {
+        self.flags = if object_size * 8 >= system.frame_size as u32 {
             Flags::SLAB_OFF
         } else {
             Flags::SLAB_ON
@@ -264,28 +772,48 @@ impl MemCache {
         // 分配的物理页帧起始位置由
         // slab结构体 + free_list数组构成
         // 第一个对象的地址需要对齐到align
-        self.init_cache_object_num();
+        self.init_object_layout();
         Ok(())
     }
+    #[track_caller]
     pub fn alloc(&self) -> Result<*mut u8,SlabError> {
+        self.alloc_with(AllocFlags::empty())
+    }
+    /// 按照分配标志分配一个对象
+    ///
+    /// 标志中的内存区域必须与cache的区域一致
+    #[track_caller]
+    pub fn alloc_with(&self, flags: AllocFlags) -> Result<*mut u8, SlabError> {
+        let caller = Location::caller();
         if self.flags.contains(Flags::DESTROY) {
             panic!("cache had been destroyed");
         }
+        flags.check()?;
+        if flags.intersects(AllocFlags::DMA | AllocFlags::DMA32) && flags.zone() != self.zone {
+            return Err(SlabError::InvalidFlags);
+        }
+        let addr = self.system().retry(flags, || self.alloc_object(flags))?;
+        if !self.debug.is_empty() {
+            self.check_alloc(addr, caller)?;
+        }
+        if flags.contains(AllocFlags::ZERO) {
+            unsafe { addr.write_bytes(0, self.object_size as usize) };
+        }
+        Ok(addr)
+    }
+    fn alloc_object(&self, flags: AllocFlags) -> Result<*mut u8, SlabError> {
         /// 先从高速缓存分配
         ///
         /// todo!(多cpu访问一致性保证 ?)
         ///
         /// 如果一个cpu上的线程正在分配内存并且以及获取了cpu_id，此时其再被抢占放到另一个cpu上可能会发生错误?
-        let cpu_id = unsafe { current_cpu_id() };
-        let array_cache = unsafe { &mut *self.array_cache[cpu_id] };
-        let mut array_cache = array_cache.inner.lock();
+        let mut array_cache = self.local_array_cache()?.inner.lock();
         if array_cache.is_empty() {
-            let mut new_objects = [0usize; PER_CPU_OBJECTS];
             let mem_cache_ptr = self as *const MemCache as *mut MemCache;
-            self.mem_cache_node
-                .alloc(mem_cache_ptr, &mut new_objects[0..array_cache.batch_count as usize])?;
             let batch_count = array_cache.batch_count as usize;
-            array_cache.push(&new_objects[0..batch_count]);
+            array_cache.push_with(batch_count, |new_objects| {
+                self.mem_cache_node.alloc(mem_cache_ptr, new_objects, flags)
+            })?;
         }
         Ok(array_cache.get())
     }
@@ -294,60 +822,278 @@ impl MemCache {
         if self.flags.contains(Flags::DESTROY) {
             panic!("cache had been destroyed");
         }
+        // 先取得本地高速缓存，cpu id无效时对象保持原样
+        let mut array_cache = self.local_array_cache()?.inner.lock();
         /// 判断此地址是否属于此cache
-        let cpu_id = unsafe { current_cpu_id() };
-        let array_cache = unsafe { &mut *self.array_cache[cpu_id] };
-        let mut array_cache = array_cache.inner.lock();
-        // self.mem_cache_node.is_in_cache(addr)?;
+        let red_zone_intact = self.debug.is_empty() || self.check_free(addr)?;
         if array_cache.is_full() {
-            let mut objects = [0usize; PER_CPU_OBJECTS];
-            let batch_count = array_cache.batch_count as usize;
-            array_cache.pop(&mut objects[0..batch_count]);
-            self.mem_cache_node.dealloc(&objects[0..batch_count]);
+            self.mem_cache_node.dealloc(&mut array_cache);
         }
         array_cache.put(addr);
+        if !red_zone_intact {
+            // 对象已经释放，返回错误只用于报告
+            return Err(SlabError::RedZoneCorrupted);
+        }
+        Ok(())
+    }
+    /// 找到属于此cache的对象所在的slab以及对象的下标
+    fn object_slab(&self, addr: *mut u8) -> Result<(&Slab, usize), SlabError> {
+        let system = self.system();
+        let slab = system.frame_map.get(system, addr as usize);
+        if slab == 0 || slab & FRAME_MAP_LARGE != 0 {
+            return Err(SlabError::NotInCache);
+        }
+        let slab = unsafe { &*(slab as *const Slab) };
+        if !core::ptr::eq(slab.cache, self) || !slab.is_in_slab(addr) {
+            return Err(SlabError::NotInCache);
+        }
+        let index = slab.object_index(addr)?;
+        Ok((slab, index))
+    }
+    /// 检查将要交给使用者的对象
+    ///
+    /// 空闲对象被改写时只报告，对象重新填充后照常交给使用者
+    fn check_alloc(&self, addr: *mut u8, caller: &'static Location<'static>) -> Result<(), SlabError> {
+        let (slab, index) = self.object_slab(addr)?;
+        if self.debug.contains(DebugFlags::DOUBLE_FREE) && slab.mark_allocated(index, true) {
+            error!("object {} in cache {} is allocated twice", index, self.cache_name);
+        }
+        if self.debug.contains(DebugFlags::POISON) {
+            let object = unsafe { core::slice::from_raw_parts_mut(addr, self.object_size as usize) };
+            let intact = match object.split_last() {
+                Some((&last, body)) => last == POISON_END && body.iter().all(|&b| b == POISON_FREE),
+                None => true,
+            };
+            if !intact {
+                error!(
+                    "object {} in cache {} (slab {:#x}) is modified after free",
+                    index,
+                    self.cache_name,
+                    slab.start()
+                );
+                if self.debug.contains(DebugFlags::STORE_USER) {
+                    if let Some(track) = unsafe { *slab.track(index) } {
+                        error!("object allocated at {} (context {:#x})", track.caller, track.context);
+                    }
+                }
+            }
+            object.fill(POISON_INUSE);
+        }
+        if self.debug.contains(DebugFlags::STORE_USER) {
+            let context = self.system().track_context.read().map_or(0, |f| f());
+            unsafe { *slab.track(index) = Some(AllocTrack { caller, context }) };
+        }
         Ok(())
     }
+    /// 清除对象的分配者记录
+    fn clear_track(&self, addr: *mut u8) {
+        if !self.debug.contains(DebugFlags::STORE_USER) {
+            return;
+        }
+        if let Ok((slab, index)) = self.object_slab(addr) {
+            unsafe { *slab.track(index) = None };
+        }
+    }
+    /// 遍历记录了分配者的对象
+    fn for_each_track(&self, mut f: impl FnMut(usize, AllocTrack)) {
+        if !self.debug.contains(DebugFlags::STORE_USER) {
+            return;
+        }
+        self.mem_cache_node.for_each_used(|slab| {
+            for index in 0..self.per_objects as usize {
+                if let Some(track) = unsafe { *slab.track(index) } {
+                    f(slab.object(index), track);
+                }
+            }
+        });
+    }
+    /// 对象的分配者，没有开启STORE_USER或者对象未分配时返回None
+    pub(crate) fn alloc_track(&self, addr: *mut u8) -> Option<AllocTrack> {
+        if !self.debug.contains(DebugFlags::STORE_USER) {
+            return None;
+        }
+        let (slab, index) = self.object_slab(addr).ok()?;
+        unsafe { *slab.track(index) }
+    }
+    /// 用空闲对象的内容填充对象
+    fn poison(&self, addr: *mut u8) {
+        let object = unsafe { core::slice::from_raw_parts_mut(addr, self.object_size as usize) };
+        object.fill(POISON_FREE);
+        if let Some(last) = object.last_mut() {
+            *last = POISON_END;
+        }
+    }
+    /// 检查释放的对象是否属于此cache并且位于对象的起始位置，
+    /// 检测重复释放时对象还需要处于已分配的状态
+    ///
+    /// 返回red zone是否完好，被改写的red zone会在报告后恢复，对象照常释放
+    fn check_free(&self, addr: *mut u8) -> Result<bool, SlabError> {
+        let (slab, index) = self.object_slab(addr)?;
+        let track = if self.debug.contains(DebugFlags::STORE_USER) {
+            unsafe { *slab.track(index) }
+        } else {
+            None
+        };
+        let red_zone_intact = !self.debug.contains(DebugFlags::RED_ZONE) || slab.check_red_zone(index);
+        if !red_zone_intact {
+            error!(
+                "red zone of object {} in cache {} (slab {:#x}) is overwritten",
+                index,
+                self.cache_name,
+                slab.start()
+            );
+            if let Some(track) = track {
+                error!("object allocated at {} (context {:#x})", track.caller, track.context);
+            }
+        }
+        if self.debug.contains(DebugFlags::DOUBLE_FREE) {
+            if !slab.mark_allocated(index, false) {
+                error!("double free of object {} in cache {}", index, self.cache_name);
+                return Err(SlabError::DoubleFree);
+            }
+        } else if slab.used_object == 0 {
+            return Err(SlabError::NotInCache);
+        }
+        if self.debug.contains(DebugFlags::STORE_USER) {
+            unsafe { *slab.track(index) = None };
+        }
+        if self.debug.contains(DebugFlags::POISON) {
+            self.poison(addr);
+        }
+        Ok(red_zone_intact)
+    }
+    /// 检查slab链表与高速缓存是否一致，只读取状态而不做修改
+    pub fn validate(&self) -> ValidationReport {
+        let mut report = ValidationReport::default();
+        self.validate_into(&mut report);
+        report
+    }
+    fn validate_into(&self, report: &mut ValidationReport) {
+        let shared = unsafe { &*self.mem_cache_node.shared };
+        for cpu in 0..self.system().cpus {
+            let array_cache = unsafe { &*self.array_cache.add(cpu) }.inner.lock();
+            let _shared = shared.inner.lock();
+            self.validate_array_cache(&array_cache, Some(cpu), report);
+        }
+        // slab链表由共享高速缓存的锁保护
+        let shared = shared.inner.lock();
+        self.validate_array_cache(&shared, None, report);
+        self.mem_cache_node.validate(self, report);
+    }
+    /// 高速缓存中的对象需要属于此cache并且在slab中处于已分配状态
+    fn validate_array_cache(
+        &self,
+        array_cache: &ArrayCacheInner,
+        cpu: Option<usize>,
+        report: &mut ValidationReport,
+    ) {
+        if array_cache.avail > array_cache.limit {
+            report.push(
+                self.cache_name,
+                Inconsistency::CacheOverflow {
+                    cpu,
+                    avail: array_cache.avail,
+                    limit: array_cache.limit,
+                },
+            );
+        }
+        let avail = min(array_cache.avail, array_cache.limit) as usize;
+        for &addr in &array_cache.entries()[..avail] {
+            match self.object_slab(addr as *mut u8) {
+                Ok((slab, index)) if slab.is_free(index) => {
+                    report.push(self.cache_name, Inconsistency::CachedObjectFree { cpu, addr });
+                }
+                Ok(_) => {}
+                Err(_) => report.push(self.cache_name, Inconsistency::ForeignCachedObject { cpu, addr }),
+            }
+        }
+    }
+    /// 遍历仍未释放的对象，返回泄漏统计
+    ///
+    /// 会先把本地高速缓存与共享高速缓存中的对象还给slab
+    fn live_objects(&self, mut f: impl FnMut(LiveObject)) -> LeakReport {
+        self.drain_all();
+        let mut report = LeakReport::default();
+        self.mem_cache_node.for_each_used(|slab| {
+            report.live_slabs += 1;
+            slab.for_each_allocated(|index| {
+                let track = if self.debug.contains(DebugFlags::STORE_USER) {
+                    unsafe { *slab.track(index) }
+                } else {
+                    None
+                };
+                report.live_objects += 1;
+                f(LiveObject {
+                    addr: slab.object(index),
+                    slab: slab.start(),
+                    track,
+                });
+            });
+        });
+        report
+    }
     /// 调用destroy会将cache管理的所有slab回收掉。
     /// 包括free/partial/full
     /// 并且对于cache本身不再可用，
     /// 由于cache本身的地址仍然会是有效的，使用者可能会再次使用已经destroy的
     /// cache分配内存，以此需要设置标志防止其再使用
-    pub fn destroy(&mut self) {
-        // 先把高速缓存的内存回收
-        for i in 0..CPUS {
-            let array_cache = self.array_cache[i];
-            // 直接回收到array_cache中
-            let cache_head = unsafe { &mut MEM_CACHE_BOOT };
-            let next_cache = mut_ref_memcache!(cache_head.list.next);
-            next_cache.dealloc(array_cache as *mut u8);
+    ///
+    /// 仍未释放的对象会通过日志逐个报告，见[`MemCache::destroy_with`]
+    pub fn destroy(&mut self) -> Result<LeakReport, SlabError> {
+        let name = self.cache_name;
+        self.destroy_with(|object| match object.track {
+            Some(track) => warn!(
+                "object {:#x} (slab {:#x}) in cache {} allocated at {} is still in use",
+                object.addr, object.slab, name, track.caller
+            ),
+            None => warn!(
+                "object {:#x} (slab {:#x}) in cache {} is still in use",
+                object.addr, object.slab, name
+            ),
+        })
+    }
+    /// 销毁cache，每个仍未释放的对象都会调用一次on_live
+    ///
+    /// 有对象未释放并且策略为[`LeakPolicy::Refuse`]时返回CacheInUse，cache保持可用
+    pub fn destroy_with(&mut self, on_live: impl FnMut(LiveObject)) -> Result<LeakReport, SlabError> {
+        if self.flags.contains(Flags::DESTROY) {
+            panic!("cache had been destroyed");
         }
+        let report = self.live_objects(on_live);
+        if report.live_objects > 0 && self.leak_policy == LeakPolicy::Refuse {
+            return Err(SlabError::CacheInUse);
+        }
+        let system = self.system();
+        // 先从cache链表中移除，遍历链表的操作不会再访问此cache
+        let caches = system.caches.write();
+        list_del!(to_list_head_ptr!(self.list));
+        drop(caches);
+        // 再把高速缓存的内存回收
+        // 直接回收到array_cache中，共享高速缓存与本地高速缓存一起回收
+        system.array_cache().dealloc(self.array_cache as *mut u8);
+        let array_entries = self.array_entries.get_mut();
+        let entries_size = array_entries.tunables.entries(system.cpus).unwrap() * core::mem::size_of::<usize>();
+        system.free_meta(array_entries.entries as *mut u8, entries_size);
+        let free_len = *self.mem_cache_node.free_list_len.read() as usize;
+        system.retained_frames.fetch_sub(free_len << self.per_frames, Ordering::Relaxed);
         self.mem_cache_node.destroy();
         //回收掉自己
         let addr = self as *const Self as *mut u8;
         self.flags = Flags::DESTROY;
-        list_del!(to_list_head_ptr!(self.list));
-        let cache_head = unsafe { &mut MEM_CACHE_BOOT };
-        cache_head.dealloc(addr);
+        system.mem_cache_boot.dealloc(addr)?;
+        Ok(report)
     }
 }
 
 
 
 
-#[inline]
-fn slab_descriptor_align_size(object_num: u32, align: u32) -> u32 {
-    align_to!(
-        object_num * core::mem::size_of::<u32>() as u32 + core::mem::size_of::<Slab>() as u32,
-        align
-    )
-}
 
 /// array_cache define\
 /// target: for multicore\
 /// limit: 可以拥有的最大对象数量\
 /// batch_count: 每次从shared或者slab系统获取的对象\
-/// entries: object address\
+/// entries: object address，指向cache统一分配的数组\
 /// 为了缓存命中率更高，
 /// 取对象的时候从后往前取，放对象的时候从前往后放
 struct ArrayCache {
@@ -366,54 +1112,86 @@ struct ArrayCacheInner {
     avail: u32,
     limit: u32,
     batch_count: u32,
-    entries: [usize; PER_CPU_OBJECTS as usize],
+    entries: *mut usize,
 }
 
 impl ArrayCacheInner {
     const fn new() -> Self {
         Self {
             avail: 0,
-            limit: PER_CPU_OBJECTS as u32,
-            batch_count: PER_CPU_OBJECTS as u32 / 2,
-            entries: [0; PER_CPU_OBJECTS],
+            limit: 0,
+            batch_count: 0,
+            entries: core::ptr::null_mut(),
         }
     }
+    /// entries需要能够容纳limit个对象
     #[inline]
-    fn init(&mut self) {
-        *self = Self::new();
+    fn init(&mut self, limit: u32, batch_count: u32, entries: *mut usize) {
+        self.avail = 0;
+        self.limit = limit;
+        self.batch_count = batch_count;
+        self.entries = entries;
+    }
+
+    #[inline]
+    fn entries(&self) -> &[usize] {
+        if self.limit == 0 {
+            return &[];
+        }
+        unsafe { core::slice::from_raw_parts(self.entries, self.limit as usize) }
+    }
+
+    #[inline]
+    fn entries_mut(&mut self) -> &mut [usize] {
+        if self.limit == 0 {
+            return &mut [];
+        }
+        unsafe { core::slice::from_raw_parts_mut(self.entries, self.limit as usize) }
     }
 
-    /// 需要保证
+    /// 需要保证空间足够
     fn push(&mut self, addrs: &[usize]) {
         //从下一层获取的batch_count个对象
         //放到array_cache中
         assert!(addrs.len() <= self.batch_count as usize);
         assert!(addrs.len() + self.avail as usize <= self.limit as usize);
-        self.entries[self.avail as usize..(self.avail + self.batch_count) as usize]
-            .copy_from_slice(addrs);
-
-        self.avail += self.batch_count;
+        let avail = self.avail as usize;
+        self.entries_mut()[avail..avail + addrs.len()].copy_from_slice(addrs);
+        self.avail += addrs.len() as u32;
+    }
+    /// 由f从前往后填充末尾的n个空闲位置，f返回实际填充的数量，只计入填充成功的部分
+    fn push_with<F>(&mut self, n: usize, f: F) -> Result<usize, SlabError>
+    where
+        F: FnOnce(&mut [usize]) -> Result<usize, SlabError>,
+    {
+        assert!(n <= self.batch_count as usize);
+        assert!(n + self.avail as usize <= self.limit as usize);
+        let avail = self.avail as usize;
+        let filled = f(&mut self.entries_mut()[avail..avail + n])?;
+        assert!(filled <= n);
+        self.avail += filled as u32;
+        Ok(filled)
     }
     fn pop_back(&mut self, addrs: &mut [usize]) {
-        assert_eq!(addrs.len(), self.batch_count as usize);
-        assert!(self.avail >= self.batch_count);
+        assert!(self.avail as usize >= addrs.len());
         //从后往前取
-        let begin = self.avail - self.batch_count;
-        addrs.copy_from_slice(&self.entries[begin as usize..(begin + self.batch_count) as usize]);
-        self.avail -= self.batch_count;
-    }
-    fn pop(&mut self, addrs: &mut [usize]) {
-        //从本层往下一层回收的batch_count个对象
-        //从前往后取
-        assert_eq!(addrs.len(), self.batch_count as usize);
-        assert_eq!(self.avail, self.limit);
-        addrs.copy_from_slice(&self.entries[0..self.batch_count as usize]);
+        let begin = self.avail as usize - addrs.len();
+        addrs.copy_from_slice(&self.entries()[begin..begin + addrs.len()]);
+        self.avail -= addrs.len() as u32;
+    }
+    /// 最旧的n个对象，用于往下一层回收
+    #[inline]
+    fn oldest(&self, n: usize) -> &[usize] {
+        assert!(self.avail as usize >= n);
+        &self.entries()[0..n]
+    }
+    /// 丢弃最旧的n个对象
+    fn pop(&mut self, n: usize) {
         //从前往后取，所以后面的对象往前移动
-        for i in self.batch_count as usize..self.avail as usize {
-            self.entries[i - self.batch_count as usize] = self.entries[i];
-        }
-
-        self.avail -= self.batch_count;
+        assert!(self.avail as usize >= n);
+        let avail = self.avail as usize;
+        self.entries_mut().copy_within(n..avail, 0);
+        self.avail -= n as u32;
     }
 
     /// 需要调用者保证存在可用的对象
@@ -421,7 +1199,7 @@ impl ArrayCacheInner {
     fn get(&mut self) -> *mut u8 {
         //从本层获取一个对象
         assert!(self.avail > 0);
-        let t = self.entries[self.avail as usize - 1] as *mut u8;
+        let t = self.entries()[self.avail as usize - 1] as *mut u8;
         self.avail -= 1;
         t
     }
@@ -429,7 +1207,8 @@ impl ArrayCacheInner {
     fn put(&mut self, addr: *mut u8) {
         //往本层放一个对象
         assert!(self.avail < self.limit);
-        self.entries[self.avail as usize] = addr as usize;
+        let avail = self.avail as usize;
+        self.entries_mut()[avail] = addr as usize;
         self.avail += 1;
     }
     #[inline]
@@ -473,17 +1252,11 @@ impl CacheNode {
         list_head_init!(self.slab_partial);
         list_head_init!(self.slab_free);
         list_head_init!(self.slab_full);
+        // cache对象所在的页帧可能被重复使用，不能假定内容为0
+        self.free_list_len = RwLock::new(0);
     }
 
-    fn set_array_cache(&mut self) -> Result<(), SlabError> {
-        //从array_cache中分配得到
-        let array_cache_addr = get_array_cache()?;
-        self.shared = array_cache_addr as *mut ArrayCache;
-        unsafe { (*self.shared).inner.lock().init() };
-        Ok(())
-    }
-
-    fn alloc_inner(&self, cache: *mut MemCache) -> Result<&mut Slab,SlabError> {
+    fn alloc_inner(&self, cache: *mut MemCache, flags: AllocFlags) -> Result<&mut Slab,SlabError> {
         let cache = unsafe{&mut *cache};
         // 先检查partial链表
         let mut slab_list = to_list_head_ptr!(self.slab_partial);
@@ -495,6 +1268,9 @@ impl CacheNode {
         } else if is_list_empty!(to_list_head_ptr!(self.slab_free)) {
             // 如果partial链表为空，则检查free链表
             // 如果free链表也为空，则需要分配新的slab
+            if flags.contains(AllocFlags::ATOMIC) {
+                return Err(SlabError::WouldBlock);
+            }
             trace!("alloc new rslab");
             // 在碰到大对象时，尽量多分配一些slab
             Slab::new(cache)?; // 创建新的slab,并加入到cache的free链表中
@@ -511,104 +1287,160 @@ impl CacheNode {
             slab.move_to(to_list_head_ptr!(self.slab_partial));
             // 空闲链表数量减少
             *self.free_list_len.write() -= 1;
+            cache.system().retained_frames.fetch_sub(1 << cache.per_frames, Ordering::Relaxed);
             slab
         };
         Ok(slab)
     }
 
-    fn alloc(&self, cache: *mut MemCache, addrs: &mut [usize])->Result<(),SlabError> {
-        // 检查共享的本地高速缓存是否有足够的对象
+    /// 从前往后填充addrs，返回实际填充的数量
+    ///
+    /// 已经取得部分对象时不返回错误，避免这些对象丢失
+    fn alloc(&self, cache: *mut MemCache, addrs: &mut [usize], flags: AllocFlags) -> Result<usize, SlabError> {
+        // 先从共享的本地高速缓存中获取对象
         let shared_array = unsafe { &mut *self.shared };
         let mut shared_array = shared_array.inner.lock();
-        if shared_array.avail >= addrs.len() as u32 {
-            // 从共享的本地高速缓存中获取对象
-            shared_array.pop_back(addrs);
-        } else {
-            // 按批次从slab中分配过来
-            // 直接返回给上一层的请求
-            let mut i = 0;
-            let mcache = unsafe{&*cache};
-            while i < shared_array.batch_count as usize{
-                let mut slab = self.alloc_inner(cache)?;
-                while slab.used_object != mcache.per_objects{
-                    let addr = slab.alloc();
-                    addrs[i] = addr as usize;
-                    i += 1;
-                    if i == shared_array.batch_count as usize{
-                        break;
-                    }
-                }
-                if slab.used_object == mcache.per_objects{
-                    slab.move_to(to_list_head_ptr!(self.slab_full));
+        let mut i = min(shared_array.avail as usize, addrs.len());
+        shared_array.pop_back(&mut addrs[..i]);
+        // 不足的部分按批次从slab中分配过来
+        // 直接返回给上一层的请求
+        let mcache = unsafe{&*cache};
+        while i < addrs.len() {
+            let slab = match self.alloc_inner(cache, flags) {
+                Ok(slab) => slab,
+                Err(_) if i > 0 => break,
+                Err(err) => return Err(err),
+            };
+            while slab.used_object != mcache.per_objects{
+                let addr = slab.alloc();
+                addrs[i] = addr as usize;
+                i += 1;
+                if i == addrs.len() {
+                    break;
                 }
             }
+            if slab.used_object == mcache.per_objects{
+                slab.move_to(to_list_head_ptr!(self.slab_full));
+            }
         }
-        Ok(())
+        Ok(i)
     }
 
     fn is_in_cache(&self, addr: *mut u8) -> Result<&mut Slab,SlabError> {
-        // 查找此对象所在的slab
+        // 通过反向映射找到此对象所在的slab
         // 这个地址可能位于partial / full
-        let slab_list = self.slab_partial.iter().find(|&slab_list| {
-            let slab = mut_ref_slab!(slab_list);
-            slab.is_in_slab(addr)
-        });
-        if slab_list.is_some() {
-            return Ok(mut_ref_slab!(slab_list.unwrap()) );
+        let cache = container_of!(self as *const CacheNode as usize, MemCache, mem_cache_node);
+        let system = unsafe { (*cache).system() };
+        let slab = system.frame_map.get(system, addr as usize);
+        if slab == 0 || slab & FRAME_MAP_LARGE != 0 {
+            return Err(SlabError::NotInCache);
         }
-        let slab_list = self.slab_full.iter().find(|&slab_list| {
-            let slab = mut_ref_slab!(slab_list) ;
-            slab.is_in_slab(addr)
-        });
-        if slab_list.is_some() {
-            return Ok(mut_ref_slab!(slab_list.unwrap()));
+        let slab = unsafe { &mut *(slab as *mut Slab) };
+        if slab.cache != cache || slab.used_object == 0 || !slab.is_in_slab(addr) {
+            return Err(SlabError::NotInCache);
+        }
+        Ok(slab)
+    }
+    /// 将一组对象还给slab，不处于已分配状态的对象只报告并跳过
+    fn dealloc_objects(&self, addrs: &[usize]) {
+        for &addr in addrs {
+            if let Err(err) = self.dealloc_inner(addr as *mut u8) {
+                // 没有开启DOUBLE_FREE时重复释放的对象会在这里被发现
+                let cache = container_of!(self as *const CacheNode as usize, MemCache, mem_cache_node);
+                error!(
+                    "object {:#x} in cache {} is not allocated: {:?}",
+                    addr,
+                    unsafe { (*cache).cache_name },
+                    err
+                );
+            }
         }
-        Err(SlabError::NotInCache)
     }
-    fn dealloc_inner(&self, addr: *mut u8) {
+    fn dealloc_inner(&self, addr: *mut u8) -> Result<(), SlabError> {
         // 查找此对象所在的slab
         // 这个地址可能位于partial / full
-        let slab = self.is_in_cache(addr).unwrap();
+        let slab = self.is_in_cache(addr)?;
         slab.dealloc(addr);
         if slab.used_object == 0 {
             // 如果slab中的对象已经全部释放，则将slab移动到free链表中
+            let cache = unsafe { &*slab.cache };
             slab.move_to(to_list_head_ptr!(self.slab_free));
             *self.free_list_len.write() +=1;
+            cache.system().retained_frames.fetch_add(1 << cache.per_frames, Ordering::Relaxed);
             // 检查是否需要释放slab回收页帧
-            self.check_and_reclaim();
+            self.check_and_reclaim(cache);
         } else {
             slab.move_to(to_list_head_ptr!(self.slab_partial));
         }
+        Ok(())
     }
-    /// 检查空闲的slab是否超过了最大值
+    /// 检查空闲的slab是否超过了cache的保留策略或者系统的预算
     /// 如果超过了，则释放多余的slab
-    fn check_and_reclaim(&self){
+    fn check_and_reclaim(&self, cache: &MemCache){
+        let system = cache.system();
+        let slab_frames = 1usize << cache.per_frames;
         let mut free_len = self.free_list_len.write();
-        if *free_len > FREE_LIST_MAX as u32 {
-            // 如果超过了最大值，则释放一部分
-            self.slab_free.iter().take(*free_len as usize - FREE_LIST_MAX).for_each(|slab_list|{
-                let slab = mut_ref_slab!(slab_list);
-                slab.reclaim();
-                list_del!(slab_list);
-            });
-            *free_len = FREE_LIST_MAX as u32;
+        let keep = cache.retention.frames(system.frame_size) / slab_frames;
+        let mut count = (*free_len as usize).saturating_sub(keep);
+        // 超过系统的预算时需要释放更多的slab
+        let over = system
+            .retained_frames
+            .load(Ordering::Relaxed)
+            .saturating_sub(system.retention_budget.load(Ordering::Relaxed));
+        count = max(count, min(*free_len as usize, (over + slab_frames - 1) / slab_frames));
+        // 如果超过了最大值，则释放一部分
+        self.reclaim_free(cache, &mut free_len, count);
+    }
+    /// 释放所有空闲的slab，返回还给页帧提供者的页帧数量
+    fn shrink(&self, cache: &MemCache) -> usize {
+        let mut free_len = self.free_list_len.write();
+        let count = *free_len as usize;
+        self.reclaim_free(cache, &mut free_len, count)
+    }
+    /// 释放free链表中的count个slab
+    fn reclaim_free(&self, cache: &MemCache, free_len: &mut u32, count: usize) -> usize {
+        if count == 0 {
+            return 0;
         }
+        self.slab_free.iter().take(count).for_each(|slab_list|{
+            let slab = mut_ref_slab!(slab_list);
+            // slab描述符可能位于被释放的页帧中，需要先移出链表
+            list_del!(slab_list);
+            slab.reclaim();
+        });
+        *free_len -= count as u32;
+        let frames = count << cache.per_frames;
+        cache.system().retained_frames.fetch_sub(frames, Ordering::Relaxed);
+        frames
     }
-    fn dealloc(&self, addrs: &[usize]) {
+    /// 将本地高速缓存中最旧的batch_count个对象回收到共享高速缓存中
+    fn dealloc(&self, array_cache: &mut ArrayCacheInner) {
+        let batch_count = array_cache.batch_count as usize;
         let shared_array = unsafe { &mut *self.shared };
         let mut shared_array = shared_array.inner.lock();
-        if shared_array.is_full() {
-            // 如果共享的本地高速缓存已经满了,
-            // 将缓存中旧的对象释放
-            let mut temp = [0usize; PER_CPU_OBJECTS];
-            let batch_count = shared_array.batch_count as usize;
-            shared_array.pop(&mut temp[0..batch_count]);
-            for i in 0..shared_array.batch_count as usize {
-                self.dealloc_inner(temp[i] as *mut u8);
+        // 调整参数时各个高速缓存依次切换，两者的batch_count可能暂时不一致
+        if (shared_array.limit as usize) < batch_count || (shared_array.batch_count as usize) < batch_count {
+            // 共享的本地高速缓存放不下，直接还给slab
+            self.dealloc_objects(array_cache.oldest(batch_count));
+        } else {
+            if shared_array.avail as usize + batch_count > shared_array.limit as usize {
+                // 如果共享的本地高速缓存已经满了,
+                // 将缓存中旧的对象释放
+                let count = min(batch_count, shared_array.avail as usize);
+                self.dealloc_objects(shared_array.oldest(count));
+                shared_array.pop(count);
             }
+            // 如果共享的本地高速缓存没有满，则将对象放入共享的本地高速缓存中
+            shared_array.push(array_cache.oldest(batch_count));
         }
-        // 如果共享的本地高速缓存没有满，则将对象放入共享的本地高速缓存中
-        shared_array.push(addrs);
+        array_cache.pop(batch_count);
+    }
+
+    /// 将一个高速缓存中的对象全部还给slab
+    fn drain(&self, array_cache: &mut ArrayCacheInner) {
+        let avail = array_cache.avail as usize;
+        self.dealloc_objects(array_cache.oldest(avail));
+        array_cache.pop(avail);
     }
 
     fn total_slabs(&self) -> usize {
@@ -624,46 +1456,97 @@ impl CacheNode {
             + self.slab_full.len() * per_objects
     }
     fn destroy(&self) {
-        // 回收本地共享高速缓存
-        let shared = self.shared;
-        // 直接释放
-        let cache_head = unsafe { &mut MEM_CACHE_BOOT };
-        let next_cache = mut_ref_memcache!(cache_head.list.next);
-        next_cache.dealloc(shared as *mut u8);
 
         self.slab_partial.iter().for_each(|slab_list| {
             let slab = mut_ref_slab!(slab_list);
-            slab.reclaim();
             // 从slab_partial链表中移除
             list_del!(slab_list);
+            slab.reclaim();
         });
         self.slab_full.iter().for_each(|slab_list| {
             let slab = mut_ref_slab!(slab_list);
-            slab.reclaim();
             // 从slab_full链表中移除
             list_del!(slab_list);
+            slab.reclaim();
         });
         self.slab_free.iter().for_each(|slab_list| {
             let slab = mut_ref_slab!(slab_list);
-            slab.reclaim();
             // 从slab_free链表中移除
             list_del!(slab_list);
+            slab.reclaim();
         });
+        *self.free_list_len.write() = 0;
     }
-}
-
-/// Slab define\
-/// cache: 指向所属的Cache\
-/// used_object：已分配的对象数量\
-/// next_free: 下一个空闲的对象\
-/// first_object: 第一个对象的地址\
-/// free_list: 数组索引用来记录空闲的对象\
-pub struct Slab {
-    list: ListHead,
-    cache: *mut MemCache,
-    used_object: u32,
-    next_free: u32,
-    color_off: u32,
+    /// 检查三个slab链表以及free_list_len，需要持有共享高速缓存的锁
+    fn validate(&self, cache: &MemCache, report: &mut ValidationReport) {
+        let name = cache.cache_name;
+        let per_objects = cache.per_objects;
+        self.slab_partial.iter().for_each(|slab_list| {
+            let slab = ref_slab!(slab_list);
+            if slab.validate(cache, report) && (slab.used_object == 0 || slab.used_object >= per_objects) {
+                let state = Inconsistency::PartialSlabState {
+                    slab: slab.start(),
+                    used: slab.used_object,
+                };
+                report.push(name, state);
+            }
+        });
+        self.slab_full.iter().for_each(|slab_list| {
+            let slab = ref_slab!(slab_list);
+            if slab.validate(cache, report) && slab.used_object != per_objects {
+                let state = Inconsistency::FullSlabNotFull {
+                    slab: slab.start(),
+                    used: slab.used_object,
+                };
+                report.push(name, state);
+            }
+        });
+        self.slab_free.iter().for_each(|slab_list| {
+            let slab = ref_slab!(slab_list);
+            if slab.validate(cache, report) && slab.used_object != 0 {
+                let state = Inconsistency::FreeSlabInUse {
+                    slab: slab.start(),
+                    used: slab.used_object,
+                };
+                report.push(name, state);
+            }
+        });
+        let recorded = *self.free_list_len.read();
+        let actual = self.slab_free.len();
+        if recorded as usize != actual {
+            report.push(name, Inconsistency::FreeListLen { recorded, actual });
+        }
+    }
+    /// 遍历包含已分配对象的slab
+    fn for_each_used(&self, mut f: impl FnMut(&Slab)) {
+        for list in [&self.slab_partial, &self.slab_full] {
+            list.iter().for_each(|slab_list| f(ref_slab!(slab_list)));
+        }
+    }
+    /// 只释放所有slab的页帧，用于销毁整个slab系统
+    fn release(&self) {
+        for list in [&self.slab_partial, &self.slab_full, &self.slab_free] {
+            list.iter().for_each(|slab_list| {
+                let slab = ref_slab!(slab_list);
+                slab.release_frames();
+            });
+        }
+        *self.free_list_len.write() = 0;
+    }
+}
+
+/// Slab define\
+/// cache: 指向所属的Cache\
+/// used_object：已分配的对象数量\
+/// next_free: 下一个空闲的对象\
+/// first_object: 第一个对象的地址\
+/// free_list: 数组索引用来记录空闲的对象\
+pub struct Slab {
+    list: ListHead,
+    cache: *mut MemCache,
+    used_object: u32,
+    next_free: u32,
+    color_off: u32,
     fist_object: usize,
     free_list: *mut u32,
 }
@@ -697,14 +1580,15 @@ impl Slab {
         // 初始化slab
         // 将slab添加到cache的slab_partial链表中
         let per_frames = cache.per_frames;
-        let start_addr = alloc_frames_for_cache(1 << per_frames);
+        let system = cache.system();
+        let start_addr = system.alloc_zone_frames(1 << per_frames, cache.zone);
         if start_addr.is_null(){
             return Err(SlabError::CantAllocFrame);
         }
         let start_addr = start_addr as usize;
         let mut slab_desc_align_size = 0; //确定slab描述符对齐后大小
         if cache.flags == Flags::SLAB_ON {
-            slab_desc_align_size = slab_descriptor_align_size(cache.per_objects, cache.align);
+            slab_desc_align_size = cache.slab_descriptor_align_size(cache.per_objects);
         }
         let mut first_object_addr = start_addr.add(slab_desc_align_size as usize);
         //需要根据cache的着色偏移来调整
@@ -714,14 +1598,33 @@ impl Slab {
             (start_addr, start_addr.add(core::mem::size_of::<Slab>()))
         } else {
             //从外面分配对象来保存slab描述符以及free_list
-            let layout = Layout::from_size_align(cache.per_objects as usize * core::mem::size_of::<u32>(), 4).unwrap();
-            let free_list_ptr = alloc_from_slab(
-                layout
-            )?;
-            let slab_ptr =
-                alloc_from_slab(slab_layout!())?;
+            let free_list_ptr = match system.alloc_meta(free_list_size!(cache)) {
+                Ok(ptr) => ptr,
+                Err(err) => {
+                    system.free_zone_frames(start_addr as *mut u8, 1 << per_frames, cache.zone);
+                    return Err(err);
+                }
+            };
+            let slab_ptr = match system.alloc_meta(core::mem::size_of::<Slab>()) {
+                Ok(ptr) => ptr,
+                Err(err) => {
+                    system.free_meta(free_list_ptr, free_list_size!(cache));
+                    system.free_zone_frames(start_addr as *mut u8, 1 << per_frames, cache.zone);
+                    return Err(err);
+                }
+            };
             (slab_ptr as usize, free_list_ptr as usize)
         };
+        // 记录slab的所有页帧
+        if let Err(err) = system.frame_map.set(system, start_addr, 1 << per_frames, slab_ptr) {
+            system.frame_map.clear(system, start_addr, 1 << per_frames);
+            if cache.flags == Flags::SLAB_OFF {
+                system.free_meta(slab_ptr as *mut u8, core::mem::size_of::<Slab>());
+                system.free_meta(free_list_addr as *mut u8, free_list_size!(cache));
+            }
+            system.free_zone_frames(start_addr as *mut u8, 1 << per_frames, cache.zone);
+            return Err(err);
+        }
         let slab = Slab {
             list: ListHead::new(),
             cache: cache as *const MemCache as *mut MemCache,
@@ -741,8 +1644,31 @@ impl Slab {
                     i,
                 );
             }
+            let (track, map, _) = cache.slab_meta_layout(cache.per_objects);
+            if cache.debug.contains(DebugFlags::STORE_USER) {
+                let track = free_list_addr.add(track) as *mut Option<AllocTrack>;
+                for i in 0..cache.per_objects as usize {
+                    track.add(i).write(None);
+                }
+            }
+            if cache.debug.contains(DebugFlags::DOUBLE_FREE) {
+                // 所有对象都处于未分配状态
+                let map = free_list_addr.add(map) as *mut u8;
+                map.write_bytes(0, (cache.per_objects as usize).div_ceil(8));
+            }
         }
         let slab = unsafe { &mut *(slab_ptr as *mut Slab) };
+        if cache.debug.contains(DebugFlags::RED_ZONE) {
+            for index in 0..cache.per_objects as usize {
+                slab.init_red_zone(index);
+            }
+        }
+        if cache.debug.contains(DebugFlags::POISON) {
+            // 新的对象同样处于空闲状态
+            for index in 0..cache.per_objects as usize {
+                cache.poison(slab.object(index) as *mut u8);
+            }
+        }
         list_head_init!(slab.list);
         trace!("{:?}", slab);
         // 加入到cache的slab_free链表中
@@ -764,9 +1690,7 @@ impl Slab {
         let per_objects = cache.per_objects;
         if self.next_free < per_objects {
             let pos = unsafe { self.free_list.add(self.next_free as usize).read() };
-            let addr = self
-                .fist_object
-                .add(pos as usize * cache.object_size as usize);
+            let addr = self.object(pos as usize);
             self.next_free += 1;
             self.used_object += 1;
             return addr as *mut u8;
@@ -775,7 +1699,7 @@ impl Slab {
     }
     fn dealloc(&mut self, addr: *mut u8) {
         let cache = unsafe { &mut *self.cache };
-        let pos = (addr as usize - self.fist_object) / cache.object_size as usize;
+        let pos = (addr as usize - self.fist_object) / cache.stride as usize;
         self.next_free -= 1;
         unsafe {
             self.free_list
@@ -796,15 +1720,24 @@ impl Slab {
         // 如果是SLAB_OFF,则需要释放slab描述符和free_list
         let cache = unsafe { &mut *self.cache };
         let per_frames = cache.per_frames;
+        let system = cache.system();
+        // 先记录起始地址，SLAB_OFF时描述符会先被释放
+        let start = self.start();
+        system.frame_map.clear(system, start, 1 << per_frames);
         if cache.flags == Flags::SLAB_OFF {
             //释放slab描述符和free_list
-            dealloc_to_slab(self as *const Slab as *mut u8,slab_layout!());
-            let layout = Layout::from_size_align(cache.per_objects as usize * core::mem::size_of::<u32>(), 4).unwrap();
-            dealloc_to_slab(self.free_list as *mut u8,layout);
-        }
-        unsafe {
-            free_frames(self.start() as *const Slab as *mut u8, 1 << per_frames);
+            let free_list = self.free_list as *mut u8;
+            system.free_meta(self as *const Slab as *mut u8, core::mem::size_of::<Slab>());
+            system.free_meta(free_list, free_list_size!(cache));
         }
+        system.free_zone_frames(start as *mut u8, 1 << per_frames, cache.zone);
+    }
+    /// 只释放页帧，SLAB_OFF的slab描述符与free_list随其所在的slab一起回收
+    fn release_frames(&self) {
+        let cache = unsafe { &*self.cache };
+        cache
+            .system()
+            .free_zone_frames(self.start() as *mut u8, 1 << cache.per_frames, cache.zone);
     }
     fn start(&self) -> usize {
         // 返回slab页面起始地址
@@ -821,165 +1754,1329 @@ impl Slab {
         list_del!(to_list_head_ptr!(self.list));
         list_add_tail!(to_list_head_ptr!(self.list), to);
     }
+    /// 第index个对象的地址
+    #[inline]
+    fn object(&self, index: usize) -> usize {
+        let cache = unsafe { &*self.cache };
+        self.fist_object + index * cache.stride as usize + cache.obj_offset as usize
+    }
+    /// 对象前后red zone的地址
+    fn red_zone(&self, index: usize) -> [*mut u64; 2] {
+        let cache = unsafe { &*self.cache };
+        let object = self.object(index);
+        [
+            (object - RED_ZONE_SIZE as usize) as *mut u64,
+            (object + cache.object_size as usize) as *mut u64,
+        ]
+    }
+    fn init_red_zone(&self, index: usize) {
+        for zone in self.red_zone(index) {
+            unsafe { zone.write(RED_ZONE_PATTERN) };
+        }
+    }
+    fn red_zone_intact(&self, index: usize) -> bool {
+        self.red_zone(index)
+            .iter()
+            .all(|&zone| unsafe { zone.read() } == RED_ZONE_PATTERN)
+    }
+    /// 检查对象前后的red zone是否完好，被改写时恢复以免重复报告
+    fn check_red_zone(&self, index: usize) -> bool {
+        let intact = self.red_zone_intact(index);
+        if !intact {
+            self.init_red_zone(index);
+        }
+        intact
+    }
+    /// 第index个对象的分配者记录
+    fn track(&self, index: usize) -> *mut Option<AllocTrack> {
+        let cache = unsafe { &*self.cache };
+        let (track, _, _) = cache.slab_meta_layout(cache.per_objects);
+        unsafe { ((self.free_list as *mut u8).add(track) as *mut Option<AllocTrack>).add(index) }
+    }
+    /// 设置对象的分配状态，返回之前的状态
+    ///
+    /// 位图紧跟在free_list数组之后，不同cpu可能同时修改同一个字节
+    fn mark_allocated(&self, index: usize, allocated: bool) -> bool {
+        let cache = unsafe { &*self.cache };
+        let (_, map, _) = cache.slab_meta_layout(cache.per_objects);
+        let map = unsafe {
+            let map = (self.free_list as *const u8).add(map) as *const AtomicU8;
+            &*map.add(index / 8)
+        };
+        let bit = 1u8 << (index % 8);
+        let old = if allocated {
+            map.fetch_or(bit, Ordering::Relaxed)
+        } else {
+            map.fetch_and(!bit, Ordering::Relaxed)
+        };
+        old & bit != 0
+    }
+    /// 遍历已分配对象的下标
+    ///
+    /// free_list中next_free之后的是空闲对象
+    fn for_each_allocated(&self, mut f: impl FnMut(usize)) {
+        let cache = unsafe { &*self.cache };
+        let free = self.free_indexes();
+        (0..cache.per_objects)
+            .filter(|index| !free.contains(index))
+            .for_each(|index| f(index as usize));
+    }
+    /// free_list中空闲对象的下标
+    fn free_indexes(&self) -> &[u32] {
+        let cache = unsafe { &*self.cache };
+        let next_free = min(self.next_free, cache.per_objects);
+        unsafe {
+            core::slice::from_raw_parts(
+                self.free_list.add(next_free as usize),
+                (cache.per_objects - next_free) as usize,
+            )
+        }
+    }
+    #[inline]
+    fn is_free(&self, index: usize) -> bool {
+        self.free_indexes().contains(&(index as u32))
+    }
+    /// 检查slab自身的计数、free_list与red zone，slab不属于cache时返回false
+    fn validate(&self, cache: &MemCache, report: &mut ValidationReport) -> bool {
+        let name = cache.cache_name;
+        if !core::ptr::eq(self.cache, cache) {
+            let slab = self as *const Slab as usize;
+            report.push(name, Inconsistency::ForeignSlab { slab });
+            return false;
+        }
+        let start = self.start();
+        if self.used_object != self.next_free {
+            let mismatch = Inconsistency::UsedCountMismatch {
+                slab: start,
+                used: self.used_object,
+                next_free: self.next_free,
+            };
+            report.push(name, mismatch);
+        }
+        let free = self.free_indexes();
+        for (i, &index) in free.iter().enumerate() {
+            if index >= cache.per_objects {
+                report.push(name, Inconsistency::FreeIndexOutOfRange { slab: start, index });
+            } else if free[..i].contains(&index) {
+                report.push(name, Inconsistency::DuplicateFreeIndex { slab: start, index });
+            }
+        }
+        if cache.debug.contains(DebugFlags::RED_ZONE) {
+            for index in 0..cache.per_objects as usize {
+                if !self.red_zone_intact(index) {
+                    report.push(name, Inconsistency::RedZoneCorrupted { slab: start, index });
+                }
+            }
+        }
+        true
+    }
+    /// 对象在slab中的下标，addr不在对象的起始位置时返回错误
+    fn object_index(&self, addr: *mut u8) -> Result<usize, SlabError> {
+        let cache = unsafe { &*self.cache };
+        let offset = (addr as usize)
+            .checked_sub(self.fist_object + cache.obj_offset as usize)
+            .ok_or(SlabError::MisalignedObject)?;
+        let index = offset / cache.stride as usize;
+        if offset % cache.stride as usize != 0 || index >= cache.per_objects as usize {
+            return Err(SlabError::MisalignedObject);
+        }
+        Ok(index)
+    }
     #[inline]
     fn is_in_slab(&self, addr: *mut u8) -> bool {
         //检查此地址是否位于slab中
         let addr = addr as usize;
         let cache = unsafe { &mut *self.cache };
         let start_addr = self.start();
-        let end_addr = start_addr.add((1 << cache.per_frames as usize) * unsafe { frame_size() });
+        let end_addr = start_addr.add((1 << cache.per_frames as usize) * cache.system().frame_size);
         (start_addr <= addr) && (addr < end_addr)
     }
 }
 
-/// 请求num个frame
-fn alloc_frames_for_cache(num: u32) -> *mut u8 {
-    trace!("alloc {} frames for cache", num);
-    unsafe { alloc_frames(num as usize) }
+/// 启动阶段的元数据区域
+///
+/// kmalloc的cache创建完成之前，本地高速缓存等元数据直接从页帧中按顺序分配，
+/// 每块区域的开头记录下一块区域与其页帧数量，这些页帧只在系统销毁时释放
+struct BootMeta {
+    /// 当前区域
+    current: *mut BootChunk,
+    /// 当前区域已使用的大小
+    used: usize,
+}
+
+struct BootChunk {
+    next: *mut BootChunk,
+    frames: usize,
+}
+
+impl BootMeta {
+    const fn new() -> Self {
+        Self {
+            current: core::ptr::null_mut(),
+            used: 0,
+        }
+    }
+
+    fn alloc(&mut self, system: &SlabSystem, size: usize) -> Result<*mut u8, SlabError> {
+        let size = align_to!(size, core::mem::size_of::<usize>());
+        let capacity = if self.current.is_null() {
+            0
+        } else {
+            let frames = unsafe { (*self.current).frames };
+            frames * system.frame_size
+        };
+        if self.used + size > capacity {
+            let header = core::mem::size_of::<BootChunk>();
+            let frames = (header + size + system.frame_size - 1) / system.frame_size;
+            let chunk = system.alloc_frames(frames as u32) as *mut BootChunk;
+            if chunk.is_null() {
+                return Err(SlabError::CantAllocFrame);
+            }
+            unsafe {
+                chunk.write(BootChunk {
+                    next: self.current,
+                    frames,
+                })
+            };
+            self.current = chunk;
+            self.used = header;
+        }
+        let addr = self.current as usize + self.used;
+        self.used += size;
+        Ok(addr as *mut u8)
+    }
+
+    /// 释放所有区域
+    fn release(&mut self, system: &SlabSystem) {
+        while !self.current.is_null() {
+            let chunk = unsafe { self.current.read() };
+            system.free_frames(self.current as *mut u8, chunk.frames as u32);
+            self.current = chunk.next;
+        }
+        self.used = 0;
+    }
+
+    fn contains(&self, system: &SlabSystem, addr: *mut u8) -> bool {
+        let addr = addr as usize;
+        let mut chunk = self.current;
+        while !chunk.is_null() {
+            let start = chunk as usize;
+            let end = start + unsafe { (*chunk).frames } * system.frame_size;
+            if start <= addr && addr < end {
+                return true;
+            }
+            chunk = unsafe { (*chunk).next };
+        }
+        false
+    }
+}
+
+/// 页帧到所属slab或大块内存的反向映射
+///
+/// 多级基数树，每个节点占用一个页帧，叶子节点中保存slab描述符的地址，
+/// 大块内存的记录地址则把最低位置1。节点在系统关闭前不会释放，查找时无需加锁
+struct FrameMap {
+    root: AtomicUsize,
+    /// 插入节点时加锁
+    lock: Mutex<()>,
+}
+
+/// 大块内存记录在反向映射中的标记
+const FRAME_MAP_LARGE: usize = 1;
+
+impl FrameMap {
+    const fn new() -> Self {
+        Self {
+            root: AtomicUsize::new(0),
+            lock: Mutex::new(()),
+        }
+    }
+
+    /// 返回(页帧大小的位数, 每级下标的位数, 级数)
+    #[inline]
+    fn shape(system: &SlabSystem) -> (u32, u32, u32) {
+        let frame_shift = system.frame_size.trailing_zeros();
+        let fanout_shift = frame_shift - core::mem::size_of::<usize>().trailing_zeros();
+        let bits = usize::BITS - frame_shift;
+        (frame_shift, fanout_shift, (bits + fanout_shift - 1) / fanout_shift)
+    }
+
+    #[inline]
+    fn slot(node: usize, index: usize) -> &'static AtomicUsize {
+        unsafe { &*(node as *const AtomicUsize).add(index) }
+    }
+
+    /// 找到addr所在页帧的叶子项，alloc为true时分配缺少的节点
+    fn leaf(&self, system: &SlabSystem, addr: usize, alloc: bool) -> Result<Option<&AtomicUsize>, SlabError> {
+        let (frame_shift, fanout_shift, levels) = Self::shape(system);
+        let mask = (1 << fanout_shift) - 1;
+        let mut slot = &self.root;
+        for level in (0..levels).rev() {
+            let mut node = slot.load(Ordering::Acquire);
+            if node == 0 {
+                if !alloc {
+                    return Ok(None);
+                }
+                node = system.alloc_frames(1) as usize;
+                if node == 0 {
+                    return Err(SlabError::CantAllocFrame);
+                }
+                unsafe { (node as *mut u8).write_bytes(0, system.frame_size) };
+                slot.store(node, Ordering::Release);
+            }
+            let index = (addr >> (frame_shift + fanout_shift * level)) & mask;
+            slot = Self::slot(node, index);
+        }
+        Ok(Some(slot))
+    }
+
+    /// 查找addr所在页帧对应的值，不存在时返回0
+    #[inline]
+    fn get(&self, system: &SlabSystem, addr: usize) -> usize {
+        match self.leaf(system, addr, false) {
+            Ok(Some(slot)) => slot.load(Ordering::Acquire),
+            _ => 0,
+        }
+    }
+
+    /// 把从start开始的frames个页帧映射到value，需要时分配中间节点
+    fn set(&self, system: &SlabSystem, start: usize, frames: usize, value: usize) -> Result<(), SlabError> {
+        let _lock = self.lock.lock();
+        for frame in 0..frames {
+            let addr = start + frame * system.frame_size;
+            if let Some(slot) = self.leaf(system, addr, true)? {
+                slot.store(value, Ordering::Release);
+            }
+        }
+        Ok(())
+    }
+
+    /// 取消从start开始的frames个页帧的映射
+    fn clear(&self, system: &SlabSystem, start: usize, frames: usize) {
+        for frame in 0..frames {
+            let addr = start + frame * system.frame_size;
+            if let Ok(Some(slot)) = self.leaf(system, addr, false) {
+                slot.store(0, Ordering::Release);
+            }
+        }
+    }
+
+    /// 释放所有节点
+    fn release(&self, system: &SlabSystem) {
+        fn release_node(system: &SlabSystem, node: usize, level: u32, fanout: usize) {
+            if level > 0 {
+                for index in 0..fanout {
+                    let child = FrameMap::slot(node, index).load(Ordering::Relaxed);
+                    if child != 0 {
+                        release_node(system, child, level - 1, fanout);
+                    }
+                }
+            }
+            system.free_frames(node as *mut u8, 1);
+        }
+        let root = self.root.swap(0, Ordering::AcqRel);
+        if root != 0 {
+            let (_, fanout_shift, levels) = Self::shape(system);
+            release_node(system, root, levels - 1, 1 << fanout_shift);
+        }
+    }
+}
+
+/// 直接从页帧提供者分配的大块内存
+struct LargeAlloc {
+    list: ListHead,
+    /// 起始地址
+    addr: usize,
+    /// 页帧数量
+    frames: usize,
+    /// 页帧所在的内存区域
+    zone: Zone,
+    /// 是否是slab系统自身的元数据
+    meta: bool,
 }
 
-fn get_array_cache()->Result<*mut u8,SlabError>{
-    let cache_head = unsafe { &mut MEM_CACHE_BOOT };
-    let next_cache = mut_ref_memcache!(cache_head.list.next);
-    assert_eq!(next_cache.cache_name,"array_cache");
-    next_cache.alloc()
+/// 大块内存分配表
+struct LargeAllocs {
+    list: ListHead,
+    /// 使用者分配的大块内存占用的页帧数量，不包括元数据
+    frames: usize,
 }
 
-/// 初始化第一个cache
-pub fn mem_cache_init()->Result<(),SlabError> {
-    unsafe {
-        list_head_init!(SLAB_CACHES);
-    }
-    let cache = unsafe { &mut MEM_CACHE_BOOT };
-    let cache_layout = cache_layout!();
-    cache.init(
-        "kmem_cache",
-        cache_layout.size() as u32,
-        cache_layout.align() as u32,
-    );
-    /// 初始化本地高速缓存信息
-    unsafe {
-        for i in 0..CPUS {
-            cache.array_cache[i] = &mut ARRAY_CACHE_FOR_BOOT[i] as *mut ArrayCache;
-        }
-        cache.mem_cache_node.shared = &mut ARRAY_CACHE_NODE_BOOT as *mut ArrayCache;
-    }
-    list_add_tail!(
-        to_list_head_ptr!(cache.list),
-        to_list_head_ptr!(SLAB_CACHES)
-    );
-
-    let array_cache_layout = array_cache_layout!();
-    /// 初始化array_cache，用于后面分配本地高速缓存对象
-    let array_cache = create(
-        "array_cache",
-        array_cache_layout.size() as u32,
-        array_cache_layout.align() as u32,
-    )?;
-    unsafe {
-        for i in 0..CPUS {
-            array_cache.array_cache[i] = &mut ARRAY_CACHE_FOR_ARRAY[i] as *mut ArrayCache;
-        }
-        array_cache.mem_cache_node.shared = &mut ARRAY_CACHE_NODE_ARRAY as *mut ArrayCache;
-    }
-    Ok(())
+macro_rules! ref_large_alloc {
+    ($addr:expr) => {
+        unsafe { &(*container_of!($addr as usize, LargeAlloc, list)) }
+    };
 }
 
-/// 创建自定义的cache
-pub fn create_mem_cache(
-    name: &'static str,
-    object_size: u32,
-    align: u32,
-) -> Result<&mut MemCache, SlabError> {
-    // 创建一个自定义cache
-    let cache_head = unsafe { &mut SLAB_CACHES };
-    let find = cache_head.iter().find(|&cache_list| {
-        let cache = mut_ref_memcache!(cache_list);
-        // //查找是否存在同名的cache
-        cache.cache_name.eq(name)
-    });
-    if find.is_some() {
-        return Err(SlabError::NameDuplicate);
-    }
-    let cache_object = create(name, object_size, align)?;
-    // 初始化高速缓存
-    cache_object.set_array_cache()?;
-    Ok(cache_object)
+/// kmalloc请求实际使用的存储
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum KmallocSlot {
+    /// kmalloc cache中的一个对象
+    Cache(*mut MemCache),
+    /// 直接分配的页帧数量
+    Large(usize),
 }
 
-fn create(name: &'static str, object_size: u32, align: u32) -> Result<&mut MemCache, SlabError> {
-    /// 从第一个初始化的cache中分配一个cached对象
-    let cache = unsafe { &mut MEM_CACHE_BOOT };
-    let cache_object_addr = cache.alloc()?;
-    let cache_object_addr =  cache_object_addr as *mut MemCache;
-    let cache_object = unsafe { &mut (*cache_object_addr) };
-    /// 初始化cache
-    cache_object.init(name, object_size, align).unwrap();
-    /// 将cache加入到SLAB_CACHES链表中
-    list_add_tail!(
-        to_list_head_ptr!(cache_object.list),
-        to_list_head_ptr!(SLAB_CACHES)
-
-    );
-    Ok(cache_object)
+/// 大块内存的统计信息
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct LargeAllocInfo {
+    /// 大块内存的数量
+    pub allocations: usize,
+    /// 占用的页帧数量
+    pub frames: usize,
 }
 
+/// slab系统没有初始化或者已经关闭
+const SYSTEM_UNINIT: u8 = 0;
+/// 正在初始化
+const SYSTEM_INITIALIZING: u8 = 1;
+/// 可以分配与释放
+const SYSTEM_READY: u8 = 2;
+/// 正在关闭，等待进行中的操作结束
+const SYSTEM_SHUTTING_DOWN: u8 = 3;
+
+/// 正在使用slab系统的操作，关闭时需要等待所有操作结束
+pub(crate) struct SystemGuard<'a> {
+    system: &'a SlabSystem,
+}
 
-/// 分配一个指定大小和对齐方式的内存
-/// 这里暂时忽略了对齐带来的影响
-pub fn alloc_from_slab(layout:Layout) -> Result<*mut u8,SlabError> {
-    // 遍历所有的cache，找到第一个能够分配的cache
-    // 跳过第一个cache，因为第一个cache是用来分配cache的
-    // 跳过第二个cache，因为第二个slab是用来分配array_cache的
-    // 不在用户创建的cache上分配
-    let cache_list = unsafe { &mut SLAB_CACHES };
-
-    let size = layout.size();
-    // 8B-8MB == 3-23
-    let index = size.next_power_of_two();
-    let index = index.trailing_zeros() as usize;
-    if index > 23 {
-        return Err(SlabError::SizeTooLarge);
-    }
-    let find = cache_list.iter().find(|&cache_list| {
-        let cache = mut_ref_memcache!(cache_list);
-        cache.object_size.trailing_zeros() as usize >= index && cache.cache_name !="array_cache" && cache.cache_name!="kmem_cache"
-    }).unwrap();
-    let cache = mut_ref_memcache!(find);
-    cache.alloc()
+impl Drop for SystemGuard<'_> {
+    fn drop(&mut self) {
+        self.system.users.fetch_sub(1, Ordering::Release);
+    }
 }
 
-/// 将分配的对象还给slab系统
-pub fn dealloc_to_slab(addr: *mut u8,layout:Layout) -> Result<(), SlabError> {
-    let cache_list = unsafe { &SLAB_CACHES };
-    let size = layout.size();
-    let index = size.next_power_of_two();
-    let index = index.trailing_zeros() as usize;
-    if index > 23 {
-        return Err(SlabError::NotInCache);
-    }
-    let find = cache_list.iter().find(|&cache_list| {
-        let cache = mut_ref_memcache!(cache_list);
-        // 查找是否存在同名的cache
-        cache.object_size.trailing_zeros() as usize == index && cache.cache_name !="array_cache" && cache.cache_name!="kmem_cache"
-    });
-    if find.is_none() {
-        return Err(SlabError::NotInCache);
-    }
-    let cache = mut_ref_memcache!(find.unwrap());
-    cache.dealloc(addr)
+/// slab系统
+///
+/// 拥有自己的kmem_cache、array_cache、kmalloc cache以及cache链表，
+/// 多个slab系统之间相互独立。
+/// 初始化之后cache链表与cache对象都指向系统自身，因此[`SlabSystem::init`]需要固定的系统，
+/// 之后不会再被移动。系统被drop时会先关闭
+pub struct SlabSystem {
+    /// Cache链表头，创建与销毁cache时修改，遍历时需要持有读锁
+    caches: RwLock<ListHead>,
+    /// 第一个cache，用于分配MemCache对象
+    mem_cache_boot: MemCache,
+    /// 启动阶段的元数据区域
+    boot_meta: Mutex<BootMeta>,
+    /// 初始化与关闭的状态
+    state: AtomicU8,
+    /// 正在使用slab系统的操作数量
+    users: AtomicUsize,
+    /// kmalloc使用的size class
+    size_classes: &'static [SizeClass],
+    /// 请求大小到size class的映射
+    size_index: SizeIndex,
+    /// 每个内存区域中每个size class对应的kmalloc cache
+    kmalloc_caches: [[*mut MemCache; SIZE_CLASS_MAX]; ZONE_COUNT],
+    /// 超过此大小的kmalloc请求直接分配页帧
+    large_threshold: usize,
+    /// 大块内存分配表
+    large: Mutex<LargeAllocs>,
+    /// 页帧到slab或大块内存的反向映射
+    frame_map: FrameMap,
+    /// kmalloc的cache是否已经创建完成
+    kmalloc_ready: bool,
+    /// 所有cache的空闲slab占用的页帧数量
+    retained_frames: AtomicUsize,
+    /// 空闲slab最多占用的页帧数量
+    retention_budget: AtomicUsize,
+    /// cpu数量
+    cpus: usize,
+    /// 页帧大小
+    frame_size: usize,
+    /// 缓存行大小
+    cache_line_size: usize,
+    frame_provider: Option<&'static dyn FrameProvider>,
+    /// 各内存区域单独的页帧提供者，没有时使用frame_provider
+    zone_providers: [Option<&'static dyn FrameProvider>; ZONE_COUNT],
+    cpu_topology: Option<&'static dyn CpuTopology>,
+    /// 记录分配者时获取上下文的函数
+    track_context: RwLock<Option<fn() -> usize>>,
+    /// 初始化之后存在指向自身的指针
+    _pinned: PhantomPinned,
 }
 
-/// 打印系统内的所有cache 信息
-pub fn print_slab_system_info() {
-    let cache_list = unsafe { &SLAB_CACHES };
-    pprintln!("There are {} caches in system:", cache_list.len());
-    pprintln!("cache_name object_size align p_frames p_objects  total_object used_object limit batch_count local_cpus shared");
-    cache_list.iter().for_each(|cache| {
-        let cache = ref_memcache!(cache);
+unsafe impl Sync for SlabSystem {}
+unsafe impl Send for SlabSystem {}
+
+impl Drop for SlabSystem {
+    fn drop(&mut self) {
+        // 没有初始化时什么都不做
+        let _ = self.shutdown();
+    }
+}
+
+impl SlabSystem {
+    pub const fn new() -> Self {
+        Self {
+            caches: RwLock::new(ListHead::new()),
+            mem_cache_boot: MemCache::new(),
+            boot_meta: Mutex::new(BootMeta::new()),
+            state: AtomicU8::new(SYSTEM_UNINIT),
+            users: AtomicUsize::new(0),
+            size_classes: &[],
+            size_index: SizeIndex::new(),
+            kmalloc_caches: [[core::ptr::null_mut(); SIZE_CLASS_MAX]; ZONE_COUNT],
+            large_threshold: usize::MAX,
+            large: Mutex::new(LargeAllocs {
+                list: ListHead::new(),
+                frames: 0,
+            }),
+            frame_map: FrameMap::new(),
+            kmalloc_ready: false,
+            retained_frames: AtomicUsize::new(0),
+            retention_budget: AtomicUsize::new(usize::MAX),
+            cpus: 1,
+            frame_size: FRAME_SIZE,
+            cache_line_size: CACHE_LINE_SIZE,
+            frame_provider: None,
+            zone_providers: [None; ZONE_COUNT],
+            cpu_topology: None,
+            track_context: RwLock::new(None),
+            _pinned: PhantomPinned,
+        }
+    }
+
+    /// 初始化slab系统，需要知道frame_size、cache_line_size与cpu数量，
+    /// 以及页帧提供者和cpu拓扑信息
+    ///
+    /// frame_size与cache_line_size需要是2的幂，重复初始化会返回错误，
+    /// 初始化失败时已经创建的cache会被回收
+    pub fn init(
+        self: Pin<&mut Self>,
+        frame_size: usize,
+        cache_line_size: usize,
+        cpus: usize,
+        frame_provider: &'static dyn FrameProvider,
+        cpu_topology: &'static dyn CpuTopology,
+    ) -> Result<(), SlabError> {
+        self.init_with(
+            frame_size,
+            cache_line_size,
+            cpus,
+            frame_provider,
+            cpu_topology,
+            KmallocConfig::default(),
+        )
+    }
+
+    /// 使用自定义的kmalloc配置初始化slab系统
+    pub fn init_with(
+        self: Pin<&mut Self>,
+        frame_size: usize,
+        cache_line_size: usize,
+        cpus: usize,
+        frame_provider: &'static dyn FrameProvider,
+        cpu_topology: &'static dyn CpuTopology,
+        config: KmallocConfig,
+    ) -> Result<(), SlabError> {
+        self.begin_init(frame_size, cache_line_size, cpus, &config)?;
+        // 初始化只在原地修改系统，不会移动它
+        let this = unsafe { self.get_unchecked_mut() };
+        this.finish_init(frame_size, cache_line_size, cpus, frame_provider, cpu_topology, config)
+    }
+
+    /// 检查初始化参数并把系统标记为正在初始化，只需要共享引用
+    ///
+    /// 参数不合法或者系统已经初始化时返回错误，此时不会修改系统
+    pub(crate) fn begin_init(
+        &self,
+        frame_size: usize,
+        cache_line_size: usize,
+        cpus: usize,
+        config: &KmallocConfig,
+    ) -> Result<(), SlabError> {
+        if !frame_size.is_power_of_two() || frame_size < MIN_FRAME_SIZE {
+            return Err(SlabError::InvalidFrameSize);
+        }
+        if !cache_line_size.is_power_of_two() {
+            return Err(SlabError::InvalidCacheLineSize);
+        }
+        if cpus == 0 {
+            return Err(SlabError::InvalidCpuCount);
+        }
+        config.check()?;
+        // 大块内存的记录需要来自描述符在slab内部的cache
+        let record = config.size_classes.iter().find(|class| {
+            class.size >= core::mem::size_of::<LargeAlloc>()
+                && class.align(frame_size) >= core::mem::align_of::<LargeAlloc>()
+        });
+        if record.is_none_or(|class| class.size * 8 >= frame_size) {
+            return Err(SlabError::InvalidFrameSize);
+        }
+        if self
+            .state
+            .compare_exchange(SYSTEM_UNINIT, SYSTEM_INITIALIZING, Ordering::AcqRel, Ordering::Acquire)
+            .is_err()
+        {
+            return Err(SlabError::AlreadyInitialized);
+        }
+        Ok(())
+    }
+
+    /// 完成初始化，需要先通过[`SlabSystem::begin_init`]检查参数并标记系统
+    ///
+    /// 失败时已经创建的cache会被回收，系统回到未初始化的状态
+    pub(crate) fn finish_init(
+        &mut self,
+        frame_size: usize,
+        cache_line_size: usize,
+        cpus: usize,
+        frame_provider: &'static dyn FrameProvider,
+        cpu_topology: &'static dyn CpuTopology,
+        config: KmallocConfig,
+    ) -> Result<(), SlabError> {
+        debug_assert_eq!(self.state.load(Ordering::Relaxed), SYSTEM_INITIALIZING);
+        // 关闭时没有重置的状态
+        self.kmalloc_ready = false;
+        self.kmalloc_caches = [[core::ptr::null_mut(); SIZE_CLASS_MAX]; ZONE_COUNT];
+        self.frame_size = frame_size;
+        self.cache_line_size = cache_line_size;
+        self.cpus = cpus;
+        self.frame_provider = Some(frame_provider);
+        self.zone_providers = [None, config.dma, config.dma32];
+        self.cpu_topology = Some(cpu_topology);
+        self.size_classes = config.size_classes;
+        self.size_index.build(config.size_classes);
+        // 最大的size class也放不下的请求同样直接分配页帧
+        let largest = config.size_classes[config.size_classes.len() - 1].size;
+        self.large_threshold = min(config.large_threshold, largest);
+        list_head_init!(self.large.get_mut().list);
+        // 初始化slab系统的两个基本cache
+        // 初始化常用的Cache
+        let res = self
+            .mem_cache_init()
+            .and_then(|_| kmalloc::init_kmalloc(self, config.debug));
+        if res.is_err() {
+            self.release();
+            self.state.store(SYSTEM_UNINIT, Ordering::Release);
+            return res;
+        }
+        self.kmalloc_ready = true;
+        self.state.store(SYSTEM_READY, Ordering::Release);
+        Ok(())
+    }
+
+    /// 开始一次使用slab系统的操作，系统没有初始化或者已经关闭时返回NotInitialized
+    ///
+    /// 返回的guard存在期间关闭操作会等待，不会回收任何页帧
+    pub(crate) fn enter(&self) -> Result<SystemGuard<'_>, SlabError> {
+        // 与shutdown中先修改状态再检查users的顺序配合，两者至少有一方能看到对方
+        self.users.fetch_add(1, Ordering::SeqCst);
+        let guard = SystemGuard { system: self };
+        if self.state.load(Ordering::SeqCst) != SYSTEM_READY {
+            return Err(SlabError::NotInitialized);
+        }
+        Ok(guard)
+    }
+
+    /// 关闭slab系统，把所有页帧还给页帧提供者
+    ///
+    /// 仍有对象未释放的cache会通过日志报告。关闭时会等待进行中的分配与释放结束，
+    /// 此后通过此系统的操作(包括此前创建的[`SlabCache`](crate::SlabCache))都会返回NotInitialized，
+    /// 此前分配的对象不能再使用
+    pub fn shutdown(&self) -> Result<ShutdownReport, SlabError> {
+        self.shutdown_with(|name, objects| {
+            warn!("cache {} still has {} live objects", name, objects);
+        })
+    }
+
+    /// 关闭slab系统，每个仍有对象未释放的cache都会调用一次on_leak
+    pub fn shutdown_with(
+        &self,
+        mut on_leak: impl FnMut(&'static str, usize),
+    ) -> Result<ShutdownReport, SlabError> {
+        if self
+            .state
+            .compare_exchange(SYSTEM_READY, SYSTEM_SHUTTING_DOWN, Ordering::SeqCst, Ordering::SeqCst)
+            .is_err()
+        {
+            return Err(SlabError::NotInitialized);
+        }
+        // 新的操作已经无法进入，等待进行中的操作结束
+        while self.users.load(Ordering::SeqCst) != 0 {
+            core::hint::spin_loop();
+        }
+        // 倒序清空高速缓存，slab回收时释放的元数据会进入先创建的cache
+        let caches = self.caches.read();
+        let mut cache_list = caches.prev;
+        while cache_list != to_list_head_ptr!(*caches) {
+            let cache = ref_memcache!(cache_list);
+            cache.drain_all();
+            cache_list = unsafe { (*cache_list).prev };
+        }
+        // kmem_cache与array_cache中只有slab系统自身的元数据
+        let mut report = ShutdownReport::default();
+        let large = self.large_info();
+        if large.allocations > 0 {
+            on_leak("large", large.allocations);
+            report.leaked_caches += 1;
+            report.leaked_objects += large.allocations;
+        }
+        caches.iter().skip(2).for_each(|cache_list| {
+            let cache = ref_memcache!(cache_list);
+            // 高速缓存已经清空，slab中已分配的对象都是未释放的
+            let live = cache.mem_cache_node.used_objects(cache.per_objects as usize);
+            let live = live.saturating_sub(cache.meta_objects.load(Ordering::Relaxed));
+            if live > 0 {
+                on_leak(cache.cache_name, live);
+                report.leaked_caches += 1;
+                report.leaked_objects += live;
+                cache.for_each_track(|addr, track| {
+                    warn!(
+                        "object {:#x} in cache {} allocated at {} (context {:#x})",
+                        addr, cache.cache_name, track.caller, track.context
+                    );
+                });
+            }
+        });
+        drop(caches);
+        self.release();
+        self.state.store(SYSTEM_UNINIT, Ordering::Release);
+        Ok(report)
+    }
+
+    /// 把所有cache的slab直接还给页帧提供者，并释放启动阶段的元数据
+    ///
+    /// 不会逐个释放cache对象、本地高速缓存等元数据，
+    /// 它们所在的slab同样会被回收。cache链表等状态留到下次初始化时重置
+    fn release(&self) {
+        // 大块内存的记录位于kmalloc cache中，需要先于cache回收
+        let mut large = self.large.lock();
+        if !large.list.next.is_null() {
+            large.list.iter().for_each(|large_list| {
+                let record = ref_large_alloc!(large_list);
+                self.free_zone_frames(record.addr as *mut u8, record.frames as u32, record.zone);
+            });
+            list_head_init!(large.list);
+            large.frames = 0;
+        }
+        drop(large);
+        let caches = self.caches.read();
+        if !caches.next.is_null() {
+            // 后创建的cache的slab描述符可能位于先创建的cache中，需要倒序回收
+            // kmem_cache位于链表头部，最后回收，此前cache对象都是可以访问的
+            let mut cache_list = caches.prev;
+            while cache_list != to_list_head_ptr!(*caches) {
+                let cache = ref_memcache!(cache_list);
+                cache_list = unsafe { (*cache_list).prev };
+                cache.mem_cache_node.release();
+            }
+        }
+        drop(caches);
+        self.boot_meta.lock().release(self);
+        self.frame_map.release(self);
+        self.retained_frames.store(0, Ordering::Relaxed);
+    }
+
+    /// 请求num个frame
+    fn alloc_frames(&self, num: u32) -> *mut u8 {
+        trace!("alloc {} frames for cache", num);
+        self.frame_provider
+            .expect("slab system has not been initialized")
+            .alloc_frames(num as usize)
+    }
+
+    fn free_frames(&self, addr: *mut u8, num: u32) {
+        self.frame_provider
+            .expect("slab system has not been initialized")
+            .free_frames(addr, num as usize)
+    }
+
+    /// 从指定的内存区域请求num个frame
+    fn alloc_zone_frames(&self, num: u32, zone: Zone) -> *mut u8 {
+        trace!("alloc {} frames in {:?}", num, zone);
+        self.zone_provider(zone).alloc_frames_in(num as usize, zone)
+    }
+
+    fn free_zone_frames(&self, addr: *mut u8, num: u32, zone: Zone) {
+        self.zone_provider(zone).free_frames_in(addr, num as usize, zone)
+    }
+
+    /// 负责该内存区域的页帧提供者
+    #[inline]
+    fn zone_provider(&self, zone: Zone) -> &'static dyn FrameProvider {
+        self.zone_providers[zone.index()]
+            .or(self.frame_provider)
+            .expect("slab system has not been initialized")
+    }
+
+    /// 是否为该内存区域创建kmalloc cache
+    pub(crate) fn has_kmalloc_zone(&self, zone: Zone) -> bool {
+        zone == Zone::Normal || self.zone_providers[zone.index()].is_some()
+    }
+
+    /// 为内存区域中的kmalloc cache生成名称，如dma-malloc-64
+    ///
+    /// 名称保存在启动阶段的元数据区域中，随系统销毁一起释放
+    pub(crate) fn kmalloc_cache_name(
+        &self,
+        zone: Zone,
+        name: &'static str,
+    ) -> Result<&'static str, SlabError> {
+        let prefix = zone.prefix();
+        if prefix.is_empty() {
+            return Ok(name);
+        }
+        let len = prefix.len() + name.len();
+        let buf = self.boot_meta.lock().alloc(self, len)?;
+        unsafe {
+            let buf = core::slice::from_raw_parts_mut(buf, len);
+            buf[..prefix.len()].copy_from_slice(prefix.as_bytes());
+            buf[prefix.len()..].copy_from_slice(name.as_bytes());
+            Ok(core::str::from_utf8_unchecked(buf))
+        }
+    }
+
+    /// 分配本地高速缓存等元数据
+    ///
+    /// kmalloc的cache创建完成之前从启动阶段的元数据区域中分配，
+    /// 超过最大size class或者对应的cache自身需要外部描述符时直接分配页帧，
+    /// 以免cache增长时再从自身分配元数据
+    fn alloc_meta(&self, size: usize) -> Result<*mut u8, SlabError> {
+        if self.kmalloc_ready {
+            let cache = self
+                .kmalloc_cache(Zone::Normal, size, core::mem::size_of::<usize>())
+                .filter(|cache| cache.flags == Flags::SLAB_ON);
+            let Some(cache) = cache else {
+                return self.alloc_large_in(self.large_frames(size)?, Zone::Normal, true);
+            };
+            let addr = cache.alloc()?;
+            cache.meta_objects.fetch_add(1, Ordering::Relaxed);
+            // 元数据不是使用者分配的对象
+            cache.clear_track(addr);
+            return Ok(addr);
+        }
+        self.boot_meta.lock().alloc(self, size)
+    }
+
+    fn free_meta(&self, addr: *mut u8, size: usize) {
+        // 启动阶段的元数据在系统销毁时统一释放
+        if self.boot_meta.lock().contains(self, addr) {
+            return;
+        }
+        if self.large_alloc(addr).is_some() {
+            let _ = self.free_large(addr);
+            return;
+        }
+        if let Some(cache) = self.kmalloc_cache(Zone::Normal, size, core::mem::size_of::<usize>()) {
+            cache.meta_objects.fetch_sub(1, Ordering::Relaxed);
+            cache.dealloc(addr);
+        }
+    }
+
+    #[inline]
+    fn current_cpu_id(&self) -> usize {
+        self.cpu_topology
+            .expect("slab system has not been initialized")
+            .current_cpu_id()
+    }
+
+    /// 第二个cache，用于分配本地高速缓存对象
+    fn array_cache(&self) -> &MemCache {
+        let next_cache = ref_memcache!(self.mem_cache_boot.list.next);
+        assert_eq!(next_cache.cache_name, "array_cache");
+        next_cache
+    }
+
+    fn get_array_cache(&self) -> Result<*mut u8, SlabError> {
+        self.array_cache().alloc()
+    }
+
+    /// 初始化第一个cache
+    fn mem_cache_init(&mut self) -> Result<(), SlabError> {
+        list_head_init!(*self.caches.get_mut());
+        let caches = to_list_head_ptr!(*self.caches.get_mut());
+        // 两个基本cache的本地高速缓存从启动阶段的元数据区域中分配
+        let array_cache_size = (self.cpus + 1) * core::mem::size_of::<ArrayCache>();
+        let array_cache_boot = self.alloc_meta(2 * array_cache_size)? as *mut ArrayCache;
+
+        let system = self as *const SlabSystem;
+        let cache_layout = cache_layout!();
+        let cache = &mut self.mem_cache_boot;
+        cache.init(
+            unsafe { &*system },
+            "kmem_cache",
+            cache_layout.size() as u32,
+            cache_layout.align() as u32,
+        )?;
+        list_add_tail!(to_list_head_ptr!(cache.list), caches);
+        // 初始化本地高速缓存信息
+        cache.set_array_cache_from(array_cache_boot, CacheTunables::default())?;
+
+        // array_cache的对象包含cpus + 1个本地高速缓存
+        let array_cache_layout = array_cache_layout!();
+        // 初始化array_cache，用于后面分配本地高速缓存对象
+        let array_cache = self.create(
+            "array_cache",
+            array_cache_size as u32,
+            array_cache_layout.align() as u32,
+        )?;
+        if array_cache.flags == Flags::SLAB_OFF {
+            // 此时kmalloc的cache尚未创建，slab描述符只能放在内部
+            array_cache.flags = Flags::SLAB_ON;
+            array_cache.init_cache_object_num();
+        }
+        array_cache.set_array_cache_from(
+            unsafe { array_cache_boot.add(self.cpus + 1) },
+            CacheTunables::default(),
+        )?;
+        // array_cache需要紧跟在第一个cache之后
+        list_add_tail!(to_list_head_ptr!(array_cache.list), caches);
+        Ok(())
+    }
+
+    /// 创建自定义的cache
+    pub(crate) fn create_mem_cache(
+        &self,
+        name: &'static str,
+        object_size: u32,
+        align: u32,
+        options: CacheOptions,
+    ) -> Result<&mut MemCache, SlabError> {
+        options.tunables.check()?;
+        // 查找同名cache与加入链表之间不能有其他cache加入
+        let caches = self.caches.write();
+        // 创建一个自定义cache
+        let find = caches.iter().find(|&cache_list| {
+            let cache = mut_ref_memcache!(cache_list);
+            // //查找是否存在同名的cache
+            cache.cache_name.eq(name)
+        });
+        if find.is_some() {
+            return Err(SlabError::NameDuplicate);
+        }
+        let cache_object = self.create(name, object_size, align)?;
+        cache_object.retention = options.retention;
+        cache_object.zone = options.zone;
+        cache_object.leak_policy = options.leak_policy;
+        if !options.debug.is_empty() {
+            // 调试信息会改变slab的布局
+            cache_object.debug = options.debug;
+            cache_object.init_object_layout();
+        }
+        // 初始化高速缓存
+        if let Err(err) = cache_object.set_array_cache(options.tunables) {
+            // cache还没有加入链表，直接回收
+            self.mem_cache_boot.dealloc(cache_object as *mut MemCache as *mut u8)?;
+            return Err(err);
+        }
+        // 初始化完成之后才将cache加入到cache链表中
+        list_add_tail!(to_list_head_ptr!(cache_object.list), to_list_head_ptr!(*caches));
+        Ok(cache_object)
+    }
+
+    /// 分配并初始化一个cache，由调用者完成高速缓存的初始化之后加入cache链表
+    fn create(&self, name: &'static str, object_size: u32, align: u32) -> Result<&mut MemCache, SlabError> {
+        // 从第一个初始化的cache中分配一个cached对象
+        let cache_object_addr = self.mem_cache_boot.alloc()?;
+        let cache_object_addr =  cache_object_addr as *mut MemCache;
+        let cache_object = unsafe { &mut (*cache_object_addr) };
+        // 初始化cache
+        cache_object.init(self, name, object_size, align).unwrap();
+        Ok(cache_object)
+    }
+
+    /// kmalloc使用的size class
+    pub(crate) fn size_classes(&self) -> &'static [SizeClass] {
+        self.size_classes
+    }
+
+    /// 页帧大小
+    pub(crate) fn frame_size(&self) -> usize {
+        self.frame_size
+    }
+
+    /// 记录内存区域中size class对应的kmalloc cache
+    pub(crate) fn set_kmalloc_cache(&mut self, zone: Zone, index: usize, cache: *mut MemCache) {
+        self.kmalloc_caches[zone.index()][index] = cache;
+    }
+
+    /// 找到内存区域中能够容纳size且满足对齐的最小size class对应的kmalloc cache
+    ///
+    /// 直接查表，用户创建的cache不参与查找
+    #[inline]
+    fn kmalloc_cache(&self, zone: Zone, size: usize, align: usize) -> Option<&mut MemCache> {
+        let index = self
+            .size_index
+            .lookup_aligned(self.size_classes, size, align, self.frame_size)?;
+        let cache = self.kmalloc_caches[zone.index()][index];
+        if cache.is_null() {
+            return None;
+        }
+        Some(unsafe { &mut *cache })
+    }
+
+    /// 确定一个kmalloc请求由内存区域中哪个kmalloc cache或多少页帧提供
+    ///
+    /// kmalloc cache中对象的对齐是其大小中2的幂的因子，最多到frame_size，
+    /// 没有满足对齐的size class或该区域没有kmalloc cache时直接分配页帧
+    pub(crate) fn kmalloc_slot(&self, layout: Layout, zone: Zone) -> Result<KmallocSlot, SlabError> {
+        if !self.kmalloc_ready {
+            return Err(SlabError::NotInitialized);
+        }
+        if layout.align() > self.frame_size {
+            return Err(SlabError::AlignTooLarge);
+        }
+        if layout.size() <= self.large_threshold {
+            // 在size class中找到第一个能够容纳该大小且满足对齐的cache
+            // 不在用户创建的cache上分配
+            if let Some(cache) = self.kmalloc_cache(zone, layout.size(), layout.align()) {
+                return Ok(KmallocSlot::Cache(cache));
+            }
+        }
+        Ok(KmallocSlot::Large(self.large_frames(layout.size())?))
+    }
+
+    /// 直接分配页帧时需要的页帧数量
+    fn large_frames(&self, size: usize) -> Result<usize, SlabError> {
+        Ok(size
+            .checked_add(self.frame_size - 1)
+            .ok_or(SlabError::SizeTooLarge)?
+            / self.frame_size)
+    }
+
+    /// slot实际可用的大小
+    ///
+    /// 不超过large_threshold，保证以任何不超过可用大小的layout释放时仍然对应同一个slot
+    pub(crate) fn slot_size(&self, slot: KmallocSlot) -> usize {
+        match slot {
+            KmallocSlot::Cache(cache) => {
+                min(unsafe { (*cache).object_size } as usize, self.large_threshold)
+            }
+            KmallocSlot::Large(frames) => frames * self.frame_size,
+        }
+    }
+
+    /// 返回kmalloc分配的内存实际可用的大小
+    pub fn ksize(&self, addr: *const u8) -> Result<usize, SlabError> {
+        let _guard = self.enter()?;
+        let addr = addr as *mut u8;
+        if let Some(record) = self.large_alloc(addr) {
+            return Ok(self.slot_size(KmallocSlot::Large(record.frames)));
+        }
+        let cache = self.object_cache(addr)?;
+        Ok(self.slot_size(KmallocSlot::Cache(cache)))
+    }
+
+    /// 找到对象所属的cache
+    fn object_cache(&self, addr: *mut u8) -> Result<*mut MemCache, SlabError> {
+        let value = self.frame_map.get(self, addr as usize);
+        if value == 0 || value & FRAME_MAP_LARGE != 0 {
+            return Err(SlabError::NotInCache);
+        }
+        let cache = unsafe { (*(value as *const Slab)).cache };
+        // 确认对象属于这个slab
+        unsafe { (*cache).mem_cache_node.is_in_cache(addr)? };
+        Ok(cache)
+    }
+
+    /// 设置记录分配者时获取上下文的函数，如返回当前任务的id
+    ///
+    /// 只对开启了STORE_USER的cache有效
+    pub fn set_track_context(&self, context: fn() -> usize) {
+        *self.track_context.write() = Some(context);
+    }
+
+    /// 查询对象的分配者
+    ///
+    /// 对象所在的cache没有开启STORE_USER或者对象未分配时返回None
+    pub fn alloc_track(&self, addr: *const u8) -> Option<AllocTrack> {
+        let _guard = self.enter().ok()?;
+        let cache = self.object_cache(addr as *mut u8).ok()?;
+        unsafe { (*cache).alloc_track(addr as *mut u8) }
+    }
+
+    /// 只根据地址释放kmalloc分配的内存或cache中的对象
+    pub fn kfree(&self, addr: *mut u8) -> Result<(), SlabError> {
+        let _guard = self.enter()?;
+        if self.large_alloc(addr).is_some() {
+            return self.free_large(addr);
+        }
+        let cache = self.object_cache(addr)?;
+        unsafe { (*cache).dealloc(addr) }
+    }
+
+    /// 分配一个指定大小和对齐方式的内存
+    #[track_caller]
+    pub(crate) fn alloc_from_slab(&self, layout:Layout) -> Result<*mut u8,SlabError> {
+        let _guard = self.enter()?;
+        match self.kmalloc_slot(layout, Zone::Normal)? {
+            KmallocSlot::Cache(cache) => unsafe { &mut *cache }.alloc(),
+            KmallocSlot::Large(frames) => self.alloc_large(frames, Zone::Normal),
+        }
+    }
+
+    /// 按照分配标志分配一个指定大小和对齐方式的内存，使用kfree释放
+    ///
+    /// 从标志选择的内存区域中的kmalloc cache分配，
+    /// 该区域没有单独的页帧提供者时没有kmalloc cache，直接从对应区域分配页帧
+    #[track_caller]
+    pub fn kmalloc(&self, layout: Layout, flags: AllocFlags) -> Result<*mut u8, SlabError> {
+        let _guard = self.enter()?;
+        flags.check()?;
+        let zone = flags.zone();
+        match self.kmalloc_slot(layout, zone)? {
+            KmallocSlot::Cache(cache) => unsafe { &*cache }.alloc_with(flags),
+            // 大块内存总是需要申请页帧
+            KmallocSlot::Large(_) if flags.contains(AllocFlags::ATOMIC) => Err(SlabError::WouldBlock),
+            KmallocSlot::Large(frames) => {
+                let addr = self.retry(flags, || self.alloc_large(frames, zone))?;
+                if flags.contains(AllocFlags::ZERO) {
+                    unsafe { addr.write_bytes(0, frames * self.frame_size) };
+                }
+                Ok(addr)
+            }
+        }
+    }
+
+    /// 分配n个大小为size、对齐为align的元素组成的数组，使用kfree释放
+    ///
+    /// 总大小溢出时返回SizeTooLarge
+    #[track_caller]
+    pub fn kmalloc_array(
+        &self,
+        n: usize,
+        size: usize,
+        align: usize,
+        flags: AllocFlags,
+    ) -> Result<*mut u8, SlabError> {
+        if !align.is_power_of_two() {
+            return Err(SlabError::InvalidAlign);
+        }
+        let size = n.checked_mul(size).ok_or(SlabError::SizeTooLarge)?;
+        let layout = Layout::from_size_align(size, align).map_err(|_| SlabError::SizeTooLarge)?;
+        self.kmalloc(layout, flags)
+    }
+
+    /// 分配清零的数组，对应linux中的kcalloc
+    #[track_caller]
+    pub fn kcalloc(
+        &self,
+        n: usize,
+        size: usize,
+        align: usize,
+        flags: AllocFlags,
+    ) -> Result<*mut u8, SlabError> {
+        self.kmalloc_array(n, size, align, flags | AllocFlags::ZERO)
+    }
+
+    /// NOFAIL时在页帧不足的情况下回收空闲slab并重试
+    ///
+    /// 每次重试前的等待时间翻倍，给其他cpu释放对象的机会
+    fn retry<F>(&self, flags: AllocFlags, mut f: F) -> Result<*mut u8, SlabError>
+    where
+        F: FnMut() -> Result<*mut u8, SlabError>,
+    {
+        let mut retries = 0;
+        loop {
+            match f() {
+                Err(SlabError::CantAllocFrame)
+                    if flags.contains(AllocFlags::NOFAIL) && retries < NOFAIL_RETRIES =>
+                {
+                    self.shrink();
+                    for _ in 0..1 << retries {
+                        core::hint::spin_loop();
+                    }
+                    retries += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    /// 清空所有cache的本地高速缓存并释放全部空闲slab，返回还给页帧提供者的页帧数量
+    pub fn shrink(&self) -> usize {
+        let Ok(_guard) = self.enter() else {
+            return 0;
+        };
+        let mut frames = 0;
+        self.caches.read().iter().for_each(|cache_list| {
+            let cache = ref_memcache!(cache_list);
+            cache.drain_all();
+            let shared = unsafe { &*cache.mem_cache_node.shared };
+            let _shared = shared.inner.lock();
+            frames += cache.mem_cache_node.shrink(cache);
+        });
+        frames
+    }
+
+    /// 将分配的对象还给slab系统
+    pub(crate) fn dealloc_to_slab(&self, addr: *mut u8,layout:Layout) -> Result<(), SlabError> {
+        let _guard = self.enter()?;
+        // 分配与释放使用相同的映射
+        match self.kmalloc_slot(layout, Zone::Normal).map_err(|_| SlabError::NotInCache)? {
+            KmallocSlot::Cache(cache) => unsafe { &mut *cache }.dealloc(addr),
+            KmallocSlot::Large(_) => self.free_large(addr),
+        }
+    }
+
+    /// 直接从页帧提供者分配大块内存，并记录到大块内存分配表中
+    fn alloc_large(&self, frames: usize, zone: Zone) -> Result<*mut u8, SlabError> {
+        self.alloc_large_in(frames, zone, false)
+    }
+
+    /// 分配大块内存，meta表示是否用于slab系统自身的元数据
+    fn alloc_large_in(&self, frames: usize, zone: Zone, meta: bool) -> Result<*mut u8, SlabError> {
+        let num = u32::try_from(frames).map_err(|_| SlabError::SizeTooLarge)?;
+        let record = self.alloc_meta(core::mem::size_of::<LargeAlloc>())? as *mut LargeAlloc;
+        let addr = self.alloc_zone_frames(num, zone);
+        if addr.is_null() {
+            self.free_meta(record as *mut u8, core::mem::size_of::<LargeAlloc>());
+            return Err(SlabError::CantAllocFrame);
+        }
+        let record = unsafe {
+            record.write(LargeAlloc {
+                list: ListHead::new(),
+                addr: addr as usize,
+                frames,
+                zone,
+                meta,
+            });
+            &mut *record
+        };
+        let value = record as *mut LargeAlloc as usize | FRAME_MAP_LARGE;
+        if let Err(err) = self.frame_map.set(self, addr as usize, frames, value) {
+            self.frame_map.clear(self, addr as usize, frames);
+            self.free_zone_frames(addr, num, zone);
+            self.free_meta(record as *mut LargeAlloc as *mut u8, core::mem::size_of::<LargeAlloc>());
+            return Err(err);
+        }
+        let mut large = self.large.lock();
+        list_add_tail!(
+            to_list_head_ptr!(record.list),
+            to_list_head_ptr!(large.list)
+        );
+        if !meta {
+            large.frames += frames;
+        }
+        Ok(addr)
+    }
+
+    /// 释放大块内存，把页帧直接还给页帧提供者
+    fn free_large(&self, addr: *mut u8) -> Result<(), SlabError> {
+        let record = self.large_alloc(addr).ok_or(SlabError::NotInCache)?;
+        let mut large = self.large.lock();
+        list_del!(to_list_head_ptr!(record.list));
+        if !record.meta {
+            large.frames -= record.frames;
+        }
+        drop(large);
+        self.frame_map.clear(self, addr as usize, record.frames);
+        self.free_zone_frames(addr, record.frames as u32, record.zone);
+        self.free_meta(record as *const LargeAlloc as *mut u8, core::mem::size_of::<LargeAlloc>());
+        Ok(())
+    }
+
+    /// 找到以addr开始的大块内存的记录
+    fn large_alloc(&self, addr: *mut u8) -> Option<&LargeAlloc> {
+        let value = self.frame_map.get(self, addr as usize);
+        if value & FRAME_MAP_LARGE == 0 {
+            return None;
+        }
+        let record = unsafe { &*((value & !FRAME_MAP_LARGE) as *const LargeAlloc) };
+        (record.addr == addr as usize).then_some(record)
+    }
+
+    /// 大块内存的统计信息
+    pub fn large_alloc_info(&self) -> LargeAllocInfo {
+        match self.enter() {
+            Ok(_guard) => self.large_info(),
+            Err(_) => LargeAllocInfo::default(),
+        }
+    }
+
+    fn large_info(&self) -> LargeAllocInfo {
+        let large = self.large.lock();
+        if large.list.next.is_null() {
+            return LargeAllocInfo::default();
+        }
+        LargeAllocInfo {
+            allocations: large
+                .list
+                .iter()
+                .filter(|&large_list| !ref_large_alloc!(large_list).meta)
+                .count(),
+            frames: large.frames,
+        }
+    }
+
+    /// 调整指定cache的本地高速缓存参数
+    pub fn set_tunables(&self, name: &str, tunables: CacheTunables) -> Result<(), SlabError> {
+        let _guard = self.enter()?;
+        let caches = self.caches.read();
+        let find = caches.iter().find(|&cache_list| {
+            let cache = ref_memcache!(cache_list);
+            cache.cache_name.eq(name)
+        });
+        match find {
+            Some(cache_list) => ref_memcache!(cache_list).set_tunables(tunables),
+            None => Err(SlabError::CacheNotFound),
+        }
+    }
+
+    /// 获取指定cache的信息
+    pub fn get_cache_info(&self, name: &str) -> Option<SlabInfo> {
+        let _guard = self.enter().ok()?;
+        self.caches
+            .read()
+            .iter()
+            .find(|&cache_list| ref_memcache!(cache_list).cache_name.eq(name))
+            .map(|cache_list| ref_memcache!(cache_list).get_cache_info())
+    }
+
+    /// 检查系统中所有cache的一致性，只读取状态而不做修改
+    pub fn validate(&self) -> ValidationReport {
+        let mut report = ValidationReport::default();
+        let Ok(_guard) = self.enter() else {
+            return report;
+        };
+        self.caches.read().iter().for_each(|cache_list| {
+            ref_memcache!(cache_list).validate_into(&mut report);
+        });
+        report
+    }
+
+    /// 设置系统中所有空闲slab的预算，超过预算的空闲slab会被立即释放
+    pub fn set_retention_budget(&self, budget: RetentionPolicy) {
+        self.retention_budget
+            .store(budget.frames(self.frame_size), Ordering::Relaxed);
+        let Ok(_guard) = self.enter() else {
+            return;
+        };
+        self.caches.read().iter().for_each(|cache_list| {
+            let cache = ref_memcache!(cache_list);
+            let shared = unsafe { &*cache.mem_cache_node.shared };
+            let _shared = shared.inner.lock();
+            cache.mem_cache_node.check_and_reclaim(cache);
+        });
+    }
+
+    /// 所有cache的空闲slab占用的页帧数量
+    pub fn retained_frames(&self) -> usize {
+        self.retained_frames.load(Ordering::Relaxed)
+    }
+
+    /// 打印系统内的所有cache 信息
+    pub fn print_info(&self) {
+        let Ok(_guard) = self.enter() else {
+            pprintln!("slab system is not initialized");
+            return;
+        };
+        let caches = self.caches.read();
+        pprintln!("There are {} caches in system:", caches.len());
+        pprintln!("cache_name object_size align p_frames p_objects  total_object used_object limit batch_count shared_limit local_cpus shared free_frames");
+        caches.iter().for_each(|cache| {
+            let cache = ref_memcache!(cache);
+            pprintln!("----------------------------------------------------------------------------------------------------------");
+            cache.print_info();
+        });
+        drop(caches);
+        let large = self.large_alloc_info();
         pprintln!("----------------------------------------------------------------------------------------------------------");
-        cache.print_info();
-    });
+        pprintln!("large allocations: {}\tframes: {}", large.allocations, large.frames);
+    }
 }
 
 
@@ -991,16 +3088,16 @@ mod array_cache_test {
     fn test_push_pop() {
         let mut cache = ArrayCache::new();
         let mut inner = cache.inner.lock();
+        let mut entries = [0; PER_CPU_OBJECTS];
+        inner.init(PER_CPU_OBJECTS as u32, PER_CPU_OBJECTS as u32 / 2, entries.as_mut_ptr());
         assert_eq!(inner.is_empty(), true);
-        assert_eq!(inner.batch_count as usize, PER_CPU_OBJECTS / 2);
-        assert_eq!(inner.limit as usize, PER_CPU_OBJECTS);
         let mut data = [0; PER_CPU_OBJECTS];
         let batch = inner.batch_count as usize;
         inner.push(&data[0..batch]);
         inner.push(&data[0..batch]);
         assert_eq!(inner.is_empty(), false);
         assert_eq!(inner.avail as usize, PER_CPU_OBJECTS);
-        inner.pop(&mut data[0..batch]);
+        inner.pop(batch);
         assert_eq!(inner.avail, PER_CPU_OBJECTS as u32 / 2);
     }
     #[test]
@@ -1008,6 +3105,8 @@ mod array_cache_test {
     fn test_push_pop_panic1() {
         let mut cache = ArrayCache::new();
         let mut inner = cache.inner.lock();
+        let mut entries = [0; PER_CPU_OBJECTS];
+        inner.init(PER_CPU_OBJECTS as u32, PER_CPU_OBJECTS as u32 / 2, entries.as_mut_ptr());
         let mut data = [0; PER_CPU_OBJECTS];
         let batch = inner.batch_count as usize;
         // 需要保证按批次送入
@@ -1018,16 +3117,34 @@ mod array_cache_test {
     fn test_push_pop_panic2() {
         let mut cache = ArrayCache::new();
         let mut inner = cache.inner.lock();
+        let mut entries = [0; PER_CPU_OBJECTS];
+        inner.init(PER_CPU_OBJECTS as u32, PER_CPU_OBJECTS as u32 / 2, entries.as_mut_ptr());
         let mut data = [0; PER_CPU_OBJECTS];
         let batch = inner.batch_count as usize;
-        // 只有在队列满的情况下才会回收对象
+        // 只能回收已有的对象
         inner.push(&data[0..batch]);
-        inner.pop(&mut data[0..batch]);
+        inner.pop(batch + 1);
+    }
+    #[test]
+    fn test_oldest() {
+        let mut cache = ArrayCache::new();
+        let mut inner = cache.inner.lock();
+        let mut entries = [0; 8];
+        inner.init(8, 4, entries.as_mut_ptr());
+        inner.push(&[1, 2, 3, 4]);
+        inner.push(&[5, 6]);
+        // 最旧的对象在前面
+        assert_eq!(inner.oldest(4), &[1, 2, 3, 4]);
+        inner.pop(4);
+        assert_eq!(inner.oldest(2), &[5, 6]);
+        assert_eq!(inner.get() as usize, 6);
     }
     #[test]
     fn test_get_put() {
         let mut cache = ArrayCache::new();
         let mut inner = cache.inner.lock();
+        let mut entries = [0; PER_CPU_OBJECTS];
+        inner.init(PER_CPU_OBJECTS as u32, PER_CPU_OBJECTS as u32 / 2, entries.as_mut_ptr());
         let mut data = [10; PER_CPU_OBJECTS];
         let batch = inner.batch_count as usize;
         // 只有在队列满的情况下才会回收对象
@@ -1048,6 +3165,8 @@ mod array_cache_test {
     fn test_get_put_panic1() {
         let mut cache = ArrayCache::new();
         let mut inner = cache.inner.lock();
+        let mut entries = [0; PER_CPU_OBJECTS];
+        inner.init(PER_CPU_OBJECTS as u32, PER_CPU_OBJECTS as u32 / 2, entries.as_mut_ptr());
         let mut data = [0; PER_CPU_OBJECTS];
         let batch = inner.batch_count as usize;
         // 只有在队列满的情况下才会回收对象
@@ -1059,23 +3178,14 @@ mod array_cache_test {
 
 #[cfg(test)]
 mod slab_test {
-    use crate::slab::{mem_cache_init, CacheNode, Flags};
+    use crate::slab::{CacheNode, Flags, SlabSystem};
     use crate::MemCache;
-    #[no_mangle]
-    unsafe fn free_frames(addr: *mut u8, num: usize) {}
-    #[no_mangle]
-    fn current_cpu_id() -> usize {
-        0
-    }
-    #[no_mangle]
-    unsafe fn alloc_frames(num: usize) -> *mut u8 {
-        core::ptr::null_mut()
-    }
 
     #[test]
     fn test_init_cache_small_obj() {
+        let system = SlabSystem::new();
         let mut cache = MemCache::new();
-        cache.init("test_cache", 128, 7);
+        cache.init(&system, "test_cache", 128, 7);
         assert_eq!(cache.align, 8);
         assert_eq!(cache.cache_name, "test_cache");
         assert_eq!(cache.object_size, 128);
@@ -1083,14 +3193,15 @@ mod slab_test {
         assert_eq!(cache.per_frames, 0);
         assert_eq!(cache.per_objects, 30);
         assert_eq!(cache.color, 5);
-        cache.init("test_cache", 127, 7);
+        cache.init(&system, "test_cache", 127, 7);
         assert_eq!(cache.object_size, 128);
     }
 
     #[test]
     fn test_init_cache_big_obj() {
+        let system = SlabSystem::new();
         let mut cache = MemCache::new();
-        cache.init("test_cache", 512, 7);
+        cache.init(&system, "test_cache", 512, 7);
         assert_eq!(cache.flags, Flags::SLAB_OFF);
         assert_eq!(cache.color, 0);
         assert_eq!(cache.per_frames, 0);
@@ -1,15 +1,45 @@
+use rslab::{CpuTopology, FrameProvider, KmallocConfig, SlabSystem};
 use std::alloc::{alloc, dealloc, Layout};
+use std::pin::Pin;
 
-#[no_mangle]
-unsafe fn free_frames(addr: *mut u8, num: usize) {
-    dealloc(addr, Layout::from_size_align(num * 4096, 4096).unwrap());
+pub struct TestProvider;
+
+impl FrameProvider for TestProvider {
+    fn alloc_frames(&self, num: usize) -> *mut u8 {
+        unsafe { alloc(Layout::from_size_align(4096 * num, 4096).unwrap()) }
+    }
+    fn free_frames(&self, addr: *mut u8, num: usize) {
+        unsafe { dealloc(addr, Layout::from_size_align(num * 4096, 4096).unwrap()) }
+    }
 }
-#[no_mangle]
-fn current_cpu_id() -> usize {
-    0
+
+impl CpuTopology for TestProvider {
+    fn current_cpu_id(&self) -> usize {
+        0
+    }
 }
-#[no_mangle]
-unsafe fn alloc_frames(num: usize) -> *mut u8 {
-    let addr = alloc(Layout::from_size_align(4096 * num, 4096).unwrap());
-    addr
+
+/// 创建一个不会被释放的slab系统，初始化之后可以通过into_ref().get_ref()共享
+pub fn leak_system() -> Pin<&'static mut SlabSystem> {
+    Pin::static_mut(Box::leak(Box::new(SlabSystem::new())))
+}
+
+/// 创建一个独立的slab系统
+pub fn new_system() -> &'static SlabSystem {
+    new_system_with(KmallocConfig::default())
+}
+
+/// 使用自定义的kmalloc配置创建一个独立的slab系统
+pub fn new_system_with(config: KmallocConfig) -> &'static SlabSystem {
+    new_system_on(&TestProvider, config)
+}
+
+/// 从指定的页帧提供者分配页帧的slab系统
+pub fn new_system_on(
+    frame_provider: &'static dyn FrameProvider,
+    config: KmallocConfig,
+) -> &'static SlabSystem {
+    let mut system = leak_system();
+    system.as_mut().init_with(4096, 64, 4, frame_provider, &TestProvider, config).unwrap();
+    system.into_ref().get_ref()
 }
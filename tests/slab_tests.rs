@@ -1,6 +1,16 @@
+#![feature(allocator_api)]
+
 mod common;
 
-use rslab::{init_slab_system, ObjectAllocator,Object, SlabCache};
+use common::{leak_system, new_system, new_system_on, new_system_with, TestProvider};
+use std::alloc::{Allocator, GlobalAlloc, Layout};
+use std::pin::pin;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use rslab::{
+    AllocFlags, CacheOptions, CacheTunables, CpuTopology, DebugFlags, FrameProvider, Inconsistency,
+    KmallocConfig, LargeAllocInfo, LeakPolicy, Object, ObjectAllocator, RetentionPolicy, SizeClass,
+    SlabAllocator, SlabCache, SlabError, SlabSystem, Zone, MIN_FRAME_SIZE,
+};
 
 #[allow(unused)]
 struct TestObj {
@@ -16,8 +26,8 @@ impl Object for TestObj{
 
 #[test]
 fn test_create_cache() {
-    init_slab_system(4096, 64);
-    let mut cache = SlabCache::<TestObj>::new("mycache0").unwrap();
+    let system = new_system();
+    let mut cache = SlabCache::<TestObj>::new_in("mycache0", system).unwrap();
     let cache_info = cache.get_cache_info();
     assert_eq!(cache_info.cache_name, "mycache0");
     assert_eq!(cache_info.align, 8);
@@ -46,13 +56,1224 @@ fn test_create_cache() {
     let cache_info = cache.get_cache_info();
     assert_eq!(cache_info.used_objects, cache_info.limit + 1);
     assert_eq!(cache_info.local_objects, cache_info.batch_count - 1);
-    cache.destroy()
+    let report = cache.destroy().unwrap();
+    assert_eq!(report.live_objects, cache_info.limit as usize + 1);
 }
 
 #[test]
 #[should_panic]
 fn test_slab_panic() {
-    let _cache = SlabCache::<TestObj>::new("my_cache1").unwrap();
+    let system = new_system();
+    let _cache = SlabCache::<TestObj>::new_in("my_cache1", system).unwrap();
     // there has been a cache named "my_cache1"
-    let _cache = SlabCache::<TestObj>::new("my_cache1").unwrap();
+    let _cache = SlabCache::<TestObj>::new_in("my_cache1", system).unwrap();
+}
+
+struct SwitchCpu(AtomicUsize);
+
+impl CpuTopology for SwitchCpu {
+    fn current_cpu_id(&self) -> usize {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+#[test]
+fn test_invalid_cpu_id() {
+    static CPU: SwitchCpu = SwitchCpu(AtomicUsize::new(0));
+    let mut system = leak_system();
+    system.as_mut().init(4096, 64, 4, &TestProvider, &CPU).unwrap();
+    let system = system.into_ref().get_ref();
+    let options = CacheOptions {
+        debug: DebugFlags::DOUBLE_FREE | DebugFlags::POISON | DebugFlags::STORE_USER,
+        ..Default::default()
+    };
+    let cache = SlabCache::<TestObj>::with_options("my_cache3", system, options).unwrap();
+    CPU.0.store(3, Ordering::Relaxed);
+    let t = cache.alloc().unwrap() as *mut TestObj;
+    // the id is outside the configured range
+    CPU.0.store(4, Ordering::Relaxed);
+    assert_eq!(cache.alloc().err(), Some(SlabError::InvalidCpuId));
+    assert_eq!(cache.dealloc(unsafe { &mut *t }), Err(SlabError::InvalidCpuId));
+    // the failed free leaves the object allocated, so it can be freed again
+    assert!(system.alloc_track(t as *mut u8).is_some());
+    CPU.0.store(3, Ordering::Relaxed);
+    assert!(cache.dealloc(unsafe { &mut *t }).is_ok());
+    assert!(cache.validate().is_ok());
+    assert_eq!(cache.get_cache_info().used_objects, 0);
+}
+
+#[test]
+fn test_unchecked_double_free() {
+    let system = new_system();
+    let cache = SlabCache::<TestObj>::new_in("unchecked_df", system).unwrap();
+    let obj = cache.alloc().unwrap() as *mut TestObj;
+    // without DOUBLE_FREE the second free is not caught here
+    assert!(cache.dealloc(unsafe { &mut *obj }).is_ok());
+    assert!(cache.dealloc(unsafe { &mut *obj }).is_ok());
+    // draining reports the extra copy and skips it instead of panicking
+    system.shrink();
+    assert_eq!(cache.get_cache_info().used_objects, 0);
+    assert!(cache.validate().is_ok());
+    let obj = cache.alloc().unwrap();
+    assert!(cache.dealloc(obj).is_ok());
+}
+
+#[test]
+fn test_independent_systems() {
+    let system0 = new_system();
+    let system1 = new_system();
+    // the same name can be used in different systems
+    let cache0 = SlabCache::<TestObj>::new_in("my_cache2", system0).unwrap();
+    let cache1 = SlabCache::<TestObj>::new_in("my_cache2", system1).unwrap();
+    let t = cache0.alloc().unwrap();
+    assert_eq!(cache0.get_cache_info().used_objects, 1);
+    assert_eq!(cache1.get_cache_info().used_objects, 0);
+    assert!(cache0.dealloc(t).is_ok());
+}
+
+#[test]
+fn test_tunables() {
+    let system = new_system();
+    let tunables = CacheTunables {
+        limit: 4,
+        batch_count: 2,
+        shared: 0,
+    };
+    let options = CacheOptions {
+        tunables,
+        ..Default::default()
+    };
+    let cache = SlabCache::<TestObj>::with_options("my_cache4", system, options).unwrap();
+    let cache_info = cache.get_cache_info();
+    assert_eq!(cache_info.limit, 4);
+    assert_eq!(cache_info.batch_count, 2);
+    assert_eq!(cache_info.shared_limit, 0);
+    let objs = (0..5).map(|_| cache.alloc().unwrap()).collect::<Vec<_>>();
+    for obj in objs {
+        assert!(cache.dealloc(obj).is_ok());
+    }
+    let cache_info = cache.get_cache_info();
+    assert_eq!(cache_info.used_objects, 0);
+    // no shared array, so the overflow goes straight back to the slabs
+    assert_eq!(cache_info.local_objects, 4);
+    assert_eq!(cache_info.shared_objects, 0);
+
+    // retune with objects still cached
+    let t = cache.alloc().unwrap();
+    let tunables = CacheTunables {
+        limit: 32,
+        batch_count: 16,
+        shared: 64,
+    };
+    assert!(cache.set_tunables(tunables).is_ok());
+    let cache_info = cache.get_cache_info();
+    assert_eq!(cache_info.limit, 32);
+    assert_eq!(cache_info.used_objects, 1);
+    assert_eq!(cache_info.local_objects, 0);
+    assert!(cache.dealloc(t).is_ok());
+    assert_eq!(cache.get_cache_info().local_objects, 1);
+
+    let bad = CacheTunables {
+        limit: 4,
+        batch_count: 8,
+        shared: 0,
+    };
+    assert!(cache.set_tunables(bad).is_err());
+    assert!(system.set_tunables("malloc-64", bad).is_err());
+    assert!(system.set_tunables("malloc-64", tunables).is_ok());
+    assert!(system.set_tunables("no_such_cache", tunables).is_err());
+}
+
+#[test]
+fn test_retention() {
+    let system = new_system();
+    let keep_none = CacheOptions {
+        retention: RetentionPolicy::Frames(0),
+        ..Default::default()
+    };
+    let cache0 = SlabCache::<TestObj>::with_options("my_cache5", system, keep_none).unwrap();
+    let cache1 = SlabCache::<TestObj>::new_in("my_cache6", system).unwrap();
+    for cache in [&cache0, &cache1] {
+        let objs = (0..200).map(|_| cache.alloc().unwrap()).collect::<Vec<_>>();
+        for obj in objs {
+            assert!(cache.dealloc(obj).is_ok());
+        }
+        // return the cached objects to the slabs
+        assert!(cache.set_tunables(CacheTunables::default()).is_ok());
+    }
+    let cache_info = cache0.get_cache_info();
+    assert_eq!(cache_info.retention, RetentionPolicy::Frames(0));
+    assert_eq!(cache_info.free_frames, 0);
+    assert_eq!(cache_info.total_objects, 0);
+    let cache_info = cache1.get_cache_info();
+    assert_eq!(cache_info.free_frames, 3);
+    assert_eq!(system.retained_frames(), 3);
+    // a smaller system-wide budget releases the empty slabs
+    system.set_retention_budget(RetentionPolicy::Bytes(4096));
+    assert_eq!(cache1.get_cache_info().free_frames, 1);
+    assert_eq!(system.retained_frames(), 1);
+}
+
+#[test]
+fn test_init_twice() {
+    let mut system = pin!(SlabSystem::new());
+    assert_eq!(
+        system.as_mut().init(4000, 64, 4, &TestProvider, &TestProvider),
+        Err(SlabError::InvalidFrameSize)
+    );
+    assert_eq!(
+        system.as_mut().init(4096, 48, 4, &TestProvider, &TestProvider),
+        Err(SlabError::InvalidCacheLineSize)
+    );
+    assert_eq!(
+        system.as_mut().init(4096, 64, 0, &TestProvider, &TestProvider),
+        Err(SlabError::InvalidCpuCount)
+    );
+    assert!(system.as_mut().init(4096, 64, 4, &TestProvider, &TestProvider).is_ok());
+    assert_eq!(
+        system.as_mut().init(4096, 64, 4, &TestProvider, &TestProvider),
+        Err(SlabError::AlreadyInitialized)
+    );
+}
+
+#[test]
+fn test_min_frame_size() {
+    let mut system = pin!(SlabSystem::new());
+    // small frames would push the small kmalloc caches off-slab
+    assert_eq!(
+        system.as_mut().init(MIN_FRAME_SIZE / 2, 64, 4, &TestProvider, &TestProvider),
+        Err(SlabError::InvalidFrameSize)
+    );
+    assert_eq!(
+        system.as_mut().init(256, 64, 4, &TestProvider, &TestProvider),
+        Err(SlabError::InvalidFrameSize)
+    );
+    // large allocation records must come from an on-slab size class
+    static CLASSES: [SizeClass; 2] = [
+        SizeClass::new("kmalloc-32", 32),
+        SizeClass::new("kmalloc-256", 256),
+    ];
+    let config = KmallocConfig {
+        size_classes: &CLASSES,
+        ..Default::default()
+    };
+    assert_eq!(
+        system.as_mut().init_with(MIN_FRAME_SIZE, 64, 4, &TestProvider, &TestProvider, config),
+        Err(SlabError::InvalidFrameSize)
+    );
+    let config = KmallocConfig {
+        debug: DebugFlags::STORE_USER | DebugFlags::DOUBLE_FREE,
+        ..Default::default()
+    };
+    system.as_mut().init_with(MIN_FRAME_SIZE, 64, 4, &TestProvider, &TestProvider, config).unwrap();
+    // off-slab caches keep growing without allocating metadata from themselves
+    for size in [64, 128, 192, 256, 512] {
+        let layout = Layout::from_size_align(size, 8).unwrap();
+        let ptrs: Vec<_> = (0..200)
+            .map(|_| system.kmalloc(layout, AllocFlags::empty()).unwrap())
+            .collect();
+        for ptr in ptrs {
+            assert!(system.kfree(ptr).is_ok());
+        }
+    }
+    assert!(system.validate().is_ok());
+}
+
+/// 只能分配有限数量页帧的提供者
+struct LimitedProvider {
+    budget: AtomicUsize,
+    outstanding: AtomicUsize,
+}
+
+impl FrameProvider for LimitedProvider {
+    fn alloc_frames(&self, num: usize) -> *mut u8 {
+        let ok = self
+            .budget
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |b| b.checked_sub(num))
+            .is_ok();
+        if !ok {
+            return core::ptr::null_mut();
+        }
+        self.outstanding.fetch_add(num, Ordering::Relaxed);
+        TestProvider.alloc_frames(num)
+    }
+    fn free_frames(&self, addr: *mut u8, num: usize) {
+        self.outstanding.fetch_sub(num, Ordering::Relaxed);
+        TestProvider.free_frames(addr, num)
+    }
+}
+
+#[test]
+fn test_init_rollback() {
+    static FRAMES: LimitedProvider = LimitedProvider {
+        budget: AtomicUsize::new(2),
+        outstanding: AtomicUsize::new(0),
+    };
+    let mut system = pin!(SlabSystem::new());
+    assert_eq!(
+        system.as_mut().init(4096, 64, 4, &FRAMES, &TestProvider),
+        Err(SlabError::CantAllocFrame)
+    );
+    assert_eq!(FRAMES.outstanding.load(Ordering::Relaxed), 0);
+    // a failed init can be retried
+    FRAMES.budget.store(usize::MAX, Ordering::Relaxed);
+    assert!(system.as_mut().init(4096, 64, 4, &FRAMES, &TestProvider).is_ok());
+}
+
+#[test]
+fn test_size_classes() {
+    static CLASSES: [SizeClass; 4] = [
+        SizeClass::new("kmalloc-32", 32),
+        SizeClass::new("kmalloc-64", 64),
+        SizeClass::new("kmalloc-96", 96),
+        SizeClass::new("kmalloc-128", 128),
+    ];
+    static BAD: [SizeClass; 2] = [
+        SizeClass::new("kmalloc-64", 64),
+        SizeClass::new("kmalloc-60", 60),
+    ];
+    static HUGE: [SizeClass; 2] = [
+        SizeClass::new("kmalloc-64", 64),
+        SizeClass::new("kmalloc-4G", 1 << 32),
+    ];
+    let mut system = pin!(SlabSystem::new());
+    let config = KmallocConfig {
+        size_classes: &BAD,
+        ..Default::default()
+    };
+    assert_eq!(
+        system.as_mut().init_with(4096, 64, 4, &TestProvider, &TestProvider, config),
+        Err(SlabError::InvalidSizeClass)
+    );
+    // object sizes of caches are u32
+    let config = KmallocConfig {
+        size_classes: &HUGE,
+        ..Default::default()
+    };
+    assert_eq!(
+        system.as_mut().init_with(4096, 64, 4, &TestProvider, &TestProvider, config),
+        Err(SlabError::InvalidSizeClass)
+    );
+    let system = new_system_with(KmallocConfig {
+        size_classes: &CLASSES,
+        ..Default::default()
+    });
+    assert!(system.get_cache_info("malloc-64").is_none());
+    let allocator = SlabAllocator::new_in(system);
+    let layout = Layout::from_size_align(65, 8).unwrap();
+    let ptr = unsafe { allocator.alloc(layout) };
+    assert_eq!(system.get_cache_info("kmalloc-96").unwrap().used_objects, 1);
+    assert_eq!(system.get_cache_info("kmalloc-128").unwrap().used_objects, 0);
+    unsafe { allocator.dealloc(ptr, layout) };
+    assert_eq!(system.get_cache_info("kmalloc-96").unwrap().used_objects, 0);
+}
+
+#[test]
+fn test_shutdown() {
+    static FRAMES: LimitedProvider = LimitedProvider {
+        budget: AtomicUsize::new(usize::MAX),
+        outstanding: AtomicUsize::new(0),
+    };
+    assert_eq!(SlabSystem::new().shutdown(), Err(SlabError::NotInitialized));
+    let system = new_system_on(&FRAMES, KmallocConfig::default());
+    let cache = SlabCache::<TestObj>::new_in("my_cache7", system).unwrap();
+    let mut objs = (0..3).map(|_| cache.alloc().unwrap()).collect::<Vec<_>>();
+    assert!(cache.dealloc(objs.pop().unwrap()).is_ok());
+    let allocator = SlabAllocator::new_in(system);
+    let layout = Layout::from_size_align(4096, 8).unwrap();
+    let ptr = unsafe { allocator.alloc(layout) };
+    unsafe { allocator.dealloc(ptr, layout) };
+    let _leak = unsafe { allocator.alloc(Layout::from_size_align(100, 8).unwrap()) };
+    let mut leaks = Vec::new();
+    let report = system
+        .shutdown_with(|name, objects| leaks.push((name, objects)))
+        .unwrap();
+    assert_eq!(report.leaked_caches, 2);
+    assert_eq!(report.leaked_objects, 3);
+    assert_eq!(leaks, vec![("malloc-128", 1), ("my_cache7", 2)]);
+    assert_eq!(FRAMES.outstanding.load(Ordering::Relaxed), 0);
+    assert_eq!(system.retained_frames(), 0);
+    assert_eq!(system.shutdown(), Err(SlabError::NotInitialized));
+    // caches and allocators bound to the system are refused afterwards
+    assert_eq!(cache.alloc().err(), Some(SlabError::NotInitialized));
+    assert!(unsafe { allocator.alloc(layout) }.is_null());
+    assert_eq!(system.kfree(_leak), Err(SlabError::NotInitialized));
+    // the system can be initialized again
+    let mut system = pin!(SlabSystem::new());
+    system.as_mut().init(4096, 64, 4, &FRAMES, &TestProvider).unwrap();
+    assert_eq!(system.shutdown().unwrap().leaked_objects, 0);
+    system.as_mut().init(4096, 64, 4, &FRAMES, &TestProvider).unwrap();
+    assert_eq!(system.shutdown().unwrap().leaked_objects, 0);
+    assert_eq!(FRAMES.outstanding.load(Ordering::Relaxed), 0);
+    // dropping an initialized system shuts it down
+    {
+        let mut system = pin!(SlabSystem::new());
+        system.as_mut().init(4096, 64, 4, &FRAMES, &TestProvider).unwrap();
+        assert!(FRAMES.outstanding.load(Ordering::Relaxed) > 0);
+    }
+    assert_eq!(FRAMES.outstanding.load(Ordering::Relaxed), 0);
+}
+
+#[test]
+fn test_shutdown_concurrent() {
+    static FRAMES: LimitedProvider = LimitedProvider {
+        budget: AtomicUsize::new(usize::MAX),
+        outstanding: AtomicUsize::new(0),
+    };
+    let system = new_system_on(&FRAMES, KmallocConfig::default());
+    let cache = SlabCache::<TestObj>::new_in("shutdown_race", system).unwrap();
+    let layout = Layout::from_size_align(64, 8).unwrap();
+    std::thread::scope(|scope| {
+        for _ in 0..4 {
+            scope.spawn(|| loop {
+                // operations either complete or are refused, never run on released memory
+                let Ok(ptr) = system.kmalloc(layout, AllocFlags::empty()) else {
+                    break;
+                };
+                let _ = system.kfree(ptr);
+                if let Ok(obj) = cache.alloc() {
+                    let _ = cache.dealloc(obj);
+                }
+            });
+        }
+        std::thread::sleep(std::time::Duration::from_millis(10));
+        assert!(system.shutdown().is_ok());
+    });
+    assert_eq!(cache.alloc().err(), Some(SlabError::NotInitialized));
+    assert_eq!(FRAMES.outstanding.load(Ordering::Relaxed), 0);
+}
+
+#[test]
+fn test_large_alloc() {
+    let system = new_system_with(KmallocConfig {
+        large_threshold: 8192,
+        ..Default::default()
+    });
+    let allocator = SlabAllocator::new_in(system);
+    let layout = Layout::from_size_align(10000, 8).unwrap();
+    let ptr = unsafe { allocator.alloc(layout) };
+    assert_eq!(ptr as usize % 4096, 0);
+    let info = system.large_alloc_info();
+    assert_eq!(info, LargeAllocInfo { allocations: 1, frames: 3 });
+    assert_eq!(system.get_cache_info("malloc_16384").unwrap().used_objects, 0);
+    // larger than the largest size class
+    let huge = Layout::from_size_align(16 << 20, 8).unwrap();
+    let huge_ptr = unsafe { allocator.alloc(huge) };
+    assert_eq!(system.large_alloc_info().frames, 3 + 4096);
+    unsafe { allocator.dealloc(ptr, layout) };
+    unsafe { allocator.dealloc(huge_ptr, huge) };
+    assert_eq!(system.large_alloc_info(), LargeAllocInfo::default());
+    assert_eq!(system.shutdown().unwrap().leaked_objects, 0);
+}
+
+#[test]
+fn test_layout_align() {
+    static CLASSES: [SizeClass; 4] = [
+        SizeClass::new("kmalloc-32", 32),
+        SizeClass::new("kmalloc-96", 96),
+        SizeClass::new("kmalloc-128", 128),
+        SizeClass::new("kmalloc-4096", 4096),
+    ];
+    let system = new_system_with(KmallocConfig {
+        size_classes: &CLASSES,
+        ..Default::default()
+    });
+    let allocator = SlabAllocator::new_in(system);
+    for (size, align, cache) in [
+        (65, 32, "kmalloc-96"),
+        (65, 64, "kmalloc-128"),
+        (4, 16, "kmalloc-32"),
+        (64, 4096, "kmalloc-4096"),
+    ] {
+        let layout = Layout::from_size_align(size, align).unwrap();
+        let ptrs = (0..20)
+            .map(|_| unsafe { allocator.alloc(layout) })
+            .collect::<Vec<_>>();
+        assert!(ptrs.iter().all(|&ptr| ptr as usize % align == 0));
+        assert_eq!(system.get_cache_info(cache).unwrap().used_objects, 20);
+        for ptr in ptrs {
+            unsafe { allocator.dealloc(ptr, layout) };
+        }
+        assert_eq!(system.get_cache_info(cache).unwrap().used_objects, 0);
+    }
+    // no size class is aligned to 8192
+    let layout = Layout::from_size_align(8, 8192).unwrap();
+    assert!(unsafe { allocator.alloc(layout) }.is_null());
+}
+
+#[test]
+fn test_realloc() {
+    let system = new_system();
+    let allocator = SlabAllocator::new_in(system);
+    let layout = Layout::from_size_align(40, 8).unwrap();
+    let ptr = unsafe { allocator.alloc(layout) };
+    unsafe { ptr.write_bytes(0x5a, 40) };
+    // the same size class is reused in place
+    let same = unsafe { allocator.realloc(ptr, layout, 64) };
+    assert_eq!(same, ptr);
+    let shrunk = unsafe { allocator.realloc(same, Layout::from_size_align(64, 8).unwrap(), 33) };
+    assert_eq!(shrunk, ptr);
+    // crossing a size class moves the data
+    let grown = unsafe { allocator.realloc(shrunk, Layout::from_size_align(33, 8).unwrap(), 100) };
+    assert_ne!(grown, ptr);
+    assert!(unsafe { std::slice::from_raw_parts(grown, 33) }.iter().all(|&b| b == 0x5a));
+    assert_eq!(system.get_cache_info("malloc-64").unwrap().used_objects, 0);
+    assert_eq!(system.get_cache_info("malloc-128").unwrap().used_objects, 1);
+    unsafe { allocator.dealloc(grown, Layout::from_size_align(100, 8).unwrap()) };
+}
+
+#[test]
+fn test_usable_size() {
+    let system = new_system();
+    let allocator = SlabAllocator::new_in(system);
+    let small = Layout::from_size_align(65, 8).unwrap();
+    let large = Layout::from_size_align(9 << 20, 8).unwrap();
+    let ptr = unsafe { allocator.alloc(small) };
+    let large_ptr = unsafe { allocator.alloc(large) };
+    assert_eq!(allocator.usable_size(ptr), Ok(128));
+    assert_eq!(system.ksize(large_ptr), Ok(9 << 20));
+    assert_eq!(system.ksize(&0u8), Err(SlabError::NotInCache));
+    // freeing with the usable size is allowed
+    unsafe { allocator.dealloc(ptr, Layout::from_size_align(128, 8).unwrap()) };
+    unsafe { allocator.dealloc(large_ptr, large) };
+    assert_eq!(system.get_cache_info("malloc-128").unwrap().used_objects, 0);
+}
+
+#[test]
+fn test_kfree() {
+    let system = new_system();
+    let allocator = SlabAllocator::new_in(system);
+    let cache = SlabCache::<TestObj>::new_in("my_cache8", system).unwrap();
+    let obj = cache.alloc().unwrap() as *mut TestObj as *mut u8;
+    let small = unsafe { allocator.alloc(Layout::from_size_align(200, 8).unwrap()) };
+    let large = unsafe { allocator.alloc(Layout::from_size_align(9 << 20, 8).unwrap()) };
+    let mut local = 0u64;
+    assert_eq!(system.kfree(&mut local as *mut u64 as *mut u8), Err(SlabError::NotInCache));
+    assert!(system.kfree(small).is_ok());
+    assert!(system.kfree(large).is_ok());
+    assert!(system.kfree(obj).is_ok());
+    assert_eq!(system.get_cache_info("malloc-256").unwrap().used_objects, 0);
+    assert_eq!(system.large_alloc_info().allocations, 0);
+    assert_eq!(cache.get_cache_info().used_objects, 0);
+    // the frames of the large allocation are no longer mapped
+    assert_eq!(system.kfree(large), Err(SlabError::NotInCache));
+}
+
+static OOM_FRAMES: LimitedProvider = LimitedProvider {
+    budget: AtomicUsize::new(usize::MAX),
+    outstanding: AtomicUsize::new(0),
+};
+static OOM_CALLS: AtomicUsize = AtomicUsize::new(0);
+static DEALLOC_ERRORS: AtomicUsize = AtomicUsize::new(0);
+
+fn refill(_layout: Layout, err: SlabError) -> bool {
+    assert_eq!(err, SlabError::CantAllocFrame);
+    // only the first failure is recovered
+    let first = OOM_CALLS.fetch_add(1, Ordering::Relaxed) == 0;
+    if first {
+        OOM_FRAMES.budget.store(usize::MAX, Ordering::Relaxed);
+    }
+    first
+}
+
+fn count_dealloc_error(_ptr: *mut u8, _layout: Layout, err: SlabError) {
+    assert_eq!(err, SlabError::NotInCache);
+    DEALLOC_ERRORS.fetch_add(1, Ordering::Relaxed);
+}
+
+#[test]
+fn test_oom() {
+    let system = new_system_on(&OOM_FRAMES, KmallocConfig::default());
+    let allocator = SlabAllocator::new_in(system)
+        .with_oom_handler(refill)
+        .with_dealloc_error_handler(count_dealloc_error);
+    // large allocations always ask the provider for frames
+    let layout = Layout::from_size_align(9 << 20, 8).unwrap();
+    OOM_FRAMES.budget.store(0, Ordering::Relaxed);
+    // the handler adds frames and the allocation is retried
+    let ptr = unsafe { allocator.alloc(layout) };
+    assert!(!ptr.is_null());
+    assert_eq!(OOM_CALLS.load(Ordering::Relaxed), 1);
+    OOM_FRAMES.budget.store(0, Ordering::Relaxed);
+    assert!(unsafe { allocator.alloc(layout) }.is_null());
+    assert_eq!(OOM_CALLS.load(Ordering::Relaxed), 2);
+    // freeing a pointer that was never allocated goes to the handler
+    let mut local = 0u64;
+    unsafe { allocator.dealloc(&mut local as *mut u64 as *mut u8, layout) };
+    assert_eq!(DEALLOC_ERRORS.load(Ordering::Relaxed), 1);
+    unsafe { allocator.dealloc(ptr, layout) };
+    assert_eq!(DEALLOC_ERRORS.load(Ordering::Relaxed), 1);
+}
+
+struct ZoneProvider {
+    budget: AtomicUsize,
+    dma: AtomicUsize,
+}
+
+impl FrameProvider for ZoneProvider {
+    fn alloc_frames(&self, num: usize) -> *mut u8 {
+        let ok = self
+            .budget
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |b| b.checked_sub(num))
+            .is_ok();
+        if !ok {
+            return core::ptr::null_mut();
+        }
+        TestProvider.alloc_frames(num)
+    }
+    fn free_frames(&self, addr: *mut u8, num: usize) {
+        self.budget.fetch_add(num, Ordering::Relaxed);
+        TestProvider.free_frames(addr, num)
+    }
+    fn alloc_frames_in(&self, num: usize, zone: Zone) -> *mut u8 {
+        if zone == Zone::Dma {
+            self.dma.fetch_add(num, Ordering::Relaxed);
+        }
+        self.alloc_frames(num)
+    }
+    fn free_frames_in(&self, addr: *mut u8, num: usize, zone: Zone) {
+        if zone == Zone::Dma {
+            self.dma.fetch_sub(num, Ordering::Relaxed);
+        }
+        self.free_frames(addr, num)
+    }
+}
+
+#[test]
+fn test_alloc_flags() {
+    static FRAMES: ZoneProvider = ZoneProvider {
+        budget: AtomicUsize::new(usize::MAX / 2),
+        dma: AtomicUsize::new(0),
+    };
+    let system = new_system_on(&FRAMES, KmallocConfig::default());
+    let cache = SlabCache::<TestObj>::new_in("flags", system).unwrap();
+    // an atomic allocation never asks the provider for frames
+    assert_eq!(cache.alloc_with(AllocFlags::ATOMIC).err(), Some(SlabError::WouldBlock));
+    let obj = cache.alloc().unwrap();
+    let atomic = cache.alloc_with(AllocFlags::ATOMIC).unwrap();
+    assert!(cache.dealloc(atomic).is_ok());
+    assert_eq!(
+        cache.alloc_with(AllocFlags::ATOMIC | AllocFlags::NOFAIL).err(),
+        Some(SlabError::InvalidFlags)
+    );
+    assert_eq!(cache.alloc_with(AllocFlags::DMA).err(), Some(SlabError::InvalidFlags));
+    obj.a = [0xff; 56];
+    assert!(cache.dealloc(obj).is_ok());
+
+    // zeroed kmalloc memory
+    let layout = Layout::from_size_align(64, 8).unwrap();
+    let ptr = system.kmalloc(layout, AllocFlags::empty()).unwrap();
+    unsafe { ptr.write_bytes(0xff, 64) };
+    assert!(system.kfree(ptr).is_ok());
+    let zeroed = system.kmalloc(layout, AllocFlags::ZERO).unwrap();
+    assert_eq!(zeroed, ptr);
+    let size = system.ksize(zeroed).unwrap();
+    assert!(unsafe { core::slice::from_raw_parts(zeroed, size) }.iter().all(|&b| b == 0));
+    assert!(system.kfree(zeroed).is_ok());
+
+    // the zone is forwarded to the provider
+    let ptr = system.kmalloc(layout, AllocFlags::DMA).unwrap();
+    assert!(FRAMES.dma.load(Ordering::Relaxed) > 0);
+    assert!(system.kfree(ptr).is_ok());
+    assert_eq!(FRAMES.dma.load(Ordering::Relaxed), 0);
+    let dma = CacheOptions {
+        zone: Zone::Dma,
+        ..Default::default()
+    };
+    let dma_cache = SlabCache::<TestObj>::with_options("flags_dma", system, dma).unwrap();
+    assert!(dma_cache.alloc_with(AllocFlags::DMA).is_ok());
+    assert_eq!(FRAMES.dma.load(Ordering::Relaxed), 1);
+
+    // no-fail allocations reclaim the empty slabs of other caches
+    let objs = (0..200).map(|_| cache.alloc().unwrap()).collect::<Vec<_>>();
+    for obj in objs {
+        assert!(cache.dealloc(obj).is_ok());
+    }
+    let other = SlabCache::<TestObj>::new_in("flags_other", system).unwrap();
+    FRAMES.budget.store(0, Ordering::Relaxed);
+    assert_eq!(other.alloc().err(), Some(SlabError::CantAllocFrame));
+    assert!(other.alloc_with(AllocFlags::NOFAIL).is_ok());
+    assert_eq!(cache.get_cache_info().total_objects, 0);
+    // nothing left to reclaim, NOFAIL gives up after a bounded number of retries
+    let layout = Layout::from_size_align(4 * 4096, 8).unwrap();
+    assert_eq!(system.kmalloc(layout, AllocFlags::NOFAIL).err(), Some(SlabError::CantAllocFrame));
+}
+
+#[test]
+fn test_zone_kmalloc() {
+    static DMA_FRAMES: LimitedProvider = LimitedProvider {
+        budget: AtomicUsize::new(usize::MAX),
+        outstanding: AtomicUsize::new(0),
+    };
+    let system = new_system_with(KmallocConfig {
+        dma: Some(&DMA_FRAMES),
+        ..Default::default()
+    });
+    let cache_info = system.get_cache_info("dma-malloc-64").unwrap();
+    assert_eq!(cache_info.zone, Zone::Dma);
+    assert_eq!(cache_info.object_size, 64);
+    assert!(system.get_cache_info("dma32-malloc-64").is_none());
+    // DMA requests are served by the dma family and its own provider
+    let layout = Layout::from_size_align(48, 8).unwrap();
+    let ptr = system.kmalloc(layout, AllocFlags::DMA).unwrap();
+    assert_eq!(system.get_cache_info("dma-malloc-64").unwrap().used_objects, 1);
+    assert_eq!(system.get_cache_info("malloc-64").unwrap().used_objects, 0);
+    assert_eq!(DMA_FRAMES.outstanding.load(Ordering::Relaxed), 1);
+    assert_eq!(system.ksize(ptr), Ok(64));
+    assert!(system.kfree(ptr).is_ok());
+    // zones without a provider fall back to whole frames
+    let ptr = system.kmalloc(layout, AllocFlags::DMA32).unwrap();
+    assert_eq!(system.large_alloc_info().allocations, 1);
+    assert!(system.kfree(ptr).is_ok());
+    system.print_info();
+    assert_eq!(system.shutdown().unwrap().leaked_objects, 0);
+    assert_eq!(DMA_FRAMES.outstanding.load(Ordering::Relaxed), 0);
+}
+
+#[test]
+fn test_alloc_array() {
+    let system = new_system();
+    assert_eq!(
+        system.kmalloc_array(usize::MAX / 2, 3, 8, AllocFlags::empty()),
+        Err(SlabError::SizeTooLarge)
+    );
+    assert_eq!(
+        system.kmalloc_array(4, 16, 24, AllocFlags::empty()),
+        Err(SlabError::InvalidAlign)
+    );
+    let ptr = system.kmalloc_array(10, 24, 8, AllocFlags::empty()).unwrap();
+    assert!(system.ksize(ptr).unwrap() >= 240);
+    unsafe { ptr.write_bytes(0xff, 240) };
+    assert!(system.kfree(ptr).is_ok());
+    let zeroed = system.kcalloc(10, 24, 8, AllocFlags::empty()).unwrap();
+    assert_eq!(zeroed, ptr);
+    assert!(unsafe { core::slice::from_raw_parts(zeroed, 240) }.iter().all(|&b| b == 0));
+    assert!(system.kfree(zeroed).is_ok());
+    // typed arrays
+    let cache = SlabCache::<TestObj>::new_in("array_cache0", system).unwrap();
+    assert_eq!(
+        cache.alloc_array(usize::MAX / 8, AllocFlags::empty()).err(),
+        Some(SlabError::SizeTooLarge)
+    );
+    let objs = cache.alloc_array(100, AllocFlags::empty()).unwrap();
+    assert_eq!(objs.len(), 100);
+    assert!(objs.iter().all(|obj| obj.a == [0; 56]));
+    assert_eq!(cache.get_cache_info().used_objects, 0);
+    assert!(cache.dealloc_array(objs).is_ok());
+}
+
+#[test]
+fn test_strict_dealloc() {
+    let system = new_system_with(KmallocConfig {
+        debug: DebugFlags::CONSISTENCY_CHECKS,
+        ..Default::default()
+    });
+    let strict = CacheOptions {
+        debug: DebugFlags::CONSISTENCY_CHECKS,
+        ..Default::default()
+    };
+    let cache = SlabCache::<TestObj>::with_options("strict", system, strict).unwrap();
+    let other = SlabCache::<TestObj>::new_in("strict_other", system).unwrap();
+    let obj = cache.alloc().unwrap();
+    let foreign = other.alloc().unwrap();
+    assert_eq!(cache.dealloc(foreign), Err(SlabError::NotInCache));
+    let mut local = TestObj::construct();
+    assert_eq!(cache.dealloc(&mut local), Err(SlabError::NotInCache));
+    let inner = unsafe { &mut *((obj as *mut TestObj as *mut u8).add(8) as *mut TestObj) };
+    assert_eq!(cache.dealloc(inner), Err(SlabError::MisalignedObject));
+    assert!(cache.dealloc(obj).is_ok());
+    assert_eq!(cache.get_cache_info().used_objects, 0);
+    // kmalloc caches are checked as well
+    let ptr = system.kmalloc(Layout::from_size_align(64, 8).unwrap(), AllocFlags::empty()).unwrap();
+    assert_eq!(system.kfree(unsafe { ptr.add(16) }), Err(SlabError::MisalignedObject));
+    assert!(system.kfree(ptr).is_ok());
+}
+
+#[test]
+fn test_double_free() {
+    let system = new_system_with(KmallocConfig {
+        debug: DebugFlags::DOUBLE_FREE,
+        ..Default::default()
+    });
+    let options = CacheOptions {
+        debug: DebugFlags::DOUBLE_FREE,
+        ..Default::default()
+    };
+    let cache = SlabCache::<TestObj>::with_options("double_free", system, options).unwrap();
+    let obj = cache.alloc().unwrap() as *mut TestObj;
+    assert!(cache.dealloc(unsafe { &mut *obj }).is_ok());
+    // the object is still in the per-cpu cache
+    assert_eq!(cache.dealloc(unsafe { &mut *obj }), Err(SlabError::DoubleFree));
+    // return the cached objects to the slabs
+    assert!(cache.set_tunables(CacheTunables::default()).is_ok());
+    assert_eq!(cache.dealloc(unsafe { &mut *obj }), Err(SlabError::DoubleFree));
+    // the rejected frees did not corrupt the cache
+    let a = cache.alloc().unwrap() as *mut TestObj;
+    let b = cache.alloc().unwrap() as *mut TestObj;
+    assert_ne!(a, b);
+    assert_eq!(cache.get_cache_info().used_objects, 2);
+    // kmalloc caches
+    let ptr = system.kmalloc(Layout::from_size_align(32, 8).unwrap(), AllocFlags::empty()).unwrap();
+    assert!(system.kfree(ptr).is_ok());
+    assert_eq!(system.kfree(ptr), Err(SlabError::DoubleFree));
+}
+
+#[test]
+fn test_red_zone() {
+    let system = new_system_with(KmallocConfig {
+        debug: DebugFlags::RED_ZONE,
+        ..Default::default()
+    });
+    let options = CacheOptions {
+        debug: DebugFlags::RED_ZONE,
+        ..Default::default()
+    };
+    let cache = SlabCache::<TestObj>::with_options("red_zone", system, options).unwrap();
+    // the guard bytes take room in the slab
+    assert!(cache.get_cache_info().per_objects < 67);
+    let a = cache.alloc().unwrap() as *mut TestObj;
+    let b = cache.alloc().unwrap() as *mut TestObj;
+    let c = cache.alloc().unwrap();
+    // overflow by one byte
+    unsafe { (a as *mut u8).add(56).write(1) };
+    assert_eq!(cache.dealloc(unsafe { &mut *a }), Err(SlabError::RedZoneCorrupted));
+    // underflow by one byte
+    unsafe { (b as *mut u8).sub(1).write(1) };
+    assert_eq!(cache.dealloc(unsafe { &mut *b }), Err(SlabError::RedZoneCorrupted));
+    assert!(cache.dealloc(c).is_ok());
+    // the corrupted objects are freed anyway and their red zones restored
+    assert_eq!(cache.get_cache_info().used_objects, 0);
+    assert!(cache.validate().is_ok());
+    // kmalloc objects keep their natural alignment
+    let layout = Layout::from_size_align(256, 256).unwrap();
+    let ptr = system.kmalloc(layout, AllocFlags::empty()).unwrap();
+    assert_eq!(ptr as usize % 256, 0);
+    unsafe { ptr.write_bytes(0xff, 256) };
+    assert!(system.kfree(ptr).is_ok());
+    let ptr = system.kmalloc(layout, AllocFlags::empty()).unwrap();
+    unsafe { ptr.add(256).write(0) };
+    assert_eq!(system.kfree(ptr), Err(SlabError::RedZoneCorrupted));
+}
+
+#[test]
+fn test_poison() {
+    let system = new_system_with(KmallocConfig {
+        debug: DebugFlags::POISON,
+        ..Default::default()
+    });
+    let options = CacheOptions {
+        debug: DebugFlags::POISON,
+        ..Default::default()
+    };
+    let cache = SlabCache::<TestObj>::with_options("poison", system, options).unwrap();
+    let obj = cache.alloc().unwrap() as *mut TestObj;
+    assert!(cache.dealloc(unsafe { &mut *obj }).is_ok());
+    // freed objects are filled with the free pattern
+    let bytes = unsafe { core::slice::from_raw_parts(obj as *const u8, 56) };
+    assert!(bytes[..55].iter().all(|&b| b == 0x6b));
+    assert_eq!(bytes[55], 0xa5);
+    // write after free is reported, the object is still handed out refilled
+    unsafe { (obj as *mut u8).add(10).write(0) };
+    let again = cache.alloc().unwrap() as *mut TestObj;
+    assert_eq!(again, obj);
+    assert!(cache.dealloc(unsafe { &mut *again }).is_ok());
+    // kmalloc memory is filled with the alloc pattern
+    let layout = Layout::from_size_align(64, 8).unwrap();
+    let ptr = system.kmalloc(layout, AllocFlags::empty()).unwrap();
+    assert!(unsafe { core::slice::from_raw_parts(ptr, 64) }.iter().all(|&b| b == 0x5a));
+    assert!(system.kfree(ptr).is_ok());
+    unsafe { ptr.write(0) };
+    let again = system.kmalloc(layout, AllocFlags::empty()).unwrap();
+    assert_eq!(again, ptr);
+    assert!(unsafe { core::slice::from_raw_parts(again, 64) }.iter().all(|&b| b == 0x5a));
+    assert!(system.kfree(again).is_ok());
+    let ptr = system.kmalloc(layout, AllocFlags::ZERO).unwrap();
+    assert!(unsafe { core::slice::from_raw_parts(ptr, 64) }.iter().all(|&b| b == 0));
+}
+
+#[test]
+fn test_store_user() {
+    let system = new_system_with(KmallocConfig {
+        debug: DebugFlags::STORE_USER,
+        ..Default::default()
+    });
+    system.set_track_context(|| 7);
+    let options = CacheOptions {
+        debug: DebugFlags::STORE_USER | DebugFlags::DOUBLE_FREE,
+        ..Default::default()
+    };
+    let cache = SlabCache::<TestObj>::with_options("store_user", system, options).unwrap();
+    let (obj, line) = (cache.alloc().unwrap() as *mut TestObj, line!());
+    let track = cache.alloc_track(unsafe { &*obj }).unwrap();
+    assert_eq!(track.caller.file(), file!());
+    assert_eq!(track.caller.line(), line);
+    assert_eq!(track.context, 7);
+    assert_eq!(system.alloc_track(obj as *const u8), Some(track));
+    // the record is cleared on free
+    assert!(cache.dealloc(unsafe { &mut *obj }).is_ok());
+    assert_eq!(system.alloc_track(obj as *const u8), None);
+    assert_eq!(cache.dealloc(unsafe { &mut *obj }), Err(SlabError::DoubleFree));
+    // kmalloc callers are recorded as well
+    let layout = Layout::from_size_align(64, 8).unwrap();
+    let (ptr, line) = (system.kmalloc(layout, AllocFlags::empty()).unwrap(), line!());
+    assert_eq!(system.alloc_track(ptr).unwrap().caller.line(), line);
+    let report = system.shutdown().unwrap();
+    assert_eq!(report.leaked_objects, 1);
+}
+
+#[test]
+fn test_destroy_leaks() {
+    let system = new_system();
+    let options = CacheOptions {
+        leak_policy: LeakPolicy::Refuse,
+        ..Default::default()
+    };
+    let mut cache = SlabCache::<TestObj>::with_options("leak_refuse", system, options).unwrap();
+    let objs: Vec<*mut TestObj> = (0..3).map(|_| cache.alloc().unwrap() as *mut TestObj).collect();
+    let freed = objs[1];
+    assert!(cache.dealloc(unsafe { &mut *freed }).is_ok());
+    // objects in the per-cpu cache are not reported
+    let mut live = Vec::new();
+    assert_eq!(cache.destroy_with(|object| live.push(object)), Err(SlabError::CacheInUse));
+    live.sort_by_key(|object| object.addr);
+    let mut expected = vec![objs[0] as usize, objs[2] as usize];
+    expected.sort();
+    assert_eq!(live.iter().map(|object| object.addr).collect::<Vec<_>>(), expected);
+    assert!(live.iter().all(|object| object.slab == live[0].slab && object.track.is_none()));
+    // the cache is still usable after a refused destroy
+    let obj = cache.alloc().unwrap() as *mut TestObj;
+    assert!(cache.dealloc(unsafe { &mut *obj }).is_ok());
+    for &obj in [objs[0], objs[2]].iter() {
+        assert!(cache.dealloc(unsafe { &mut *obj }).is_ok());
+    }
+    assert_eq!(cache.destroy(), Ok(Default::default()));
+
+    // the default policy reports the leaks and destroys the cache anyway
+    let mut cache = SlabCache::<TestObj>::new_in("leak_warn", system).unwrap();
+    cache.alloc().unwrap();
+    let report = cache.destroy().unwrap();
+    assert_eq!(report.live_objects, 1);
+    assert_eq!(report.live_slabs, 1);
+    assert!(SlabCache::<TestObj>::new_in("leak_warn", system).is_ok());
+}
+
+#[test]
+fn test_validate() {
+    let system = new_system();
+    let options = CacheOptions {
+        debug: DebugFlags::RED_ZONE,
+        ..Default::default()
+    };
+    let cache = SlabCache::<TestObj>::with_options("validate", system, options).unwrap();
+    cache.alloc().unwrap();
+    let b = cache.alloc().unwrap() as *mut TestObj;
+    assert!(cache.validate().is_ok());
+    assert!(system.validate().is_ok());
+    // red zones are reported without being repaired
+    unsafe { (b as *mut u8).add(56).write(0) };
+    for _ in 0..2 {
+        let report = cache.validate();
+        assert_eq!(report.count(), 1);
+        let issue = report.iter().next().unwrap();
+        assert_eq!(issue.cache, "validate");
+        assert!(matches!(issue.issue, Inconsistency::RedZoneCorrupted { .. }));
+    }
+    assert_eq!(cache.dealloc(unsafe { &mut *b }), Err(SlabError::RedZoneCorrupted));
+    assert!(system.validate().is_ok());
+    assert_eq!(cache.get_cache_info().used_objects, 1);
+    // an unchecked double free puts the same index twice in the free list
+    let cache = SlabCache::<TestObj>::new_in("validate_df", system).unwrap();
+    let a2 = cache.alloc().unwrap() as *mut TestObj;
+    let _b2 = cache.alloc().unwrap();
+    assert!(cache.dealloc(unsafe { &mut *a2 }).is_ok());
+    assert!(cache.dealloc(unsafe { &mut *a2 }).is_ok());
+    let info = cache.get_cache_info();
+    let tunables = CacheTunables {
+        limit: info.limit,
+        batch_count: info.batch_count,
+        shared: info.shared_limit,
+    };
+    assert!(cache.set_tunables(tunables).is_ok());
+    let report = system.validate();
+    assert!(report.count() >= 1);
+    assert!(report.iter().all(|issue| issue.cache == "validate_df"));
+    assert!(report
+        .iter()
+        .any(|issue| matches!(issue.issue, Inconsistency::DuplicateFreeIndex { .. })));
+}
+
+#[test]
+fn test_meta_beyond_size_classes() {
+    static CLASSES: [SizeClass; 4] = [
+        SizeClass::new("kmalloc-32", 32),
+        SizeClass::new("kmalloc-64", 64),
+        SizeClass::new("kmalloc-96", 96),
+        SizeClass::new("kmalloc-128", 128),
+    ];
+    let system = new_system_with(KmallocConfig {
+        size_classes: &CLASSES,
+        ..Default::default()
+    });
+    // the per-cpu entries no longer fit in any size class
+    let mut cache = SlabCache::<TestObj>::new_in("meta_frames", system).unwrap();
+    let obj = cache.alloc().unwrap() as *mut TestObj;
+    let tunables = CacheTunables {
+        limit: 240,
+        batch_count: 60,
+        shared: 480,
+    };
+    assert!(cache.set_tunables(tunables).is_ok());
+    assert!(cache.dealloc(unsafe { &mut *obj }).is_ok());
+    // metadata frames are not reported as large allocations
+    assert_eq!(system.large_alloc_info(), LargeAllocInfo::default());
+    assert_eq!(cache.destroy(), Ok(Default::default()));
+    assert_eq!(system.shutdown().unwrap().leaked_objects, 0);
+}
+
+#[test]
+fn test_grow_meta_failure() {
+    #[allow(unused)]
+    struct BigObj([u8; 512]);
+    impl Object for BigObj {
+        fn construct() -> Self {
+            BigObj([0; 512])
+        }
+    }
+    static FRAMES: LimitedProvider = LimitedProvider {
+        budget: AtomicUsize::new(usize::MAX),
+        outstanding: AtomicUsize::new(0),
+    };
+    static CLASSES: [SizeClass; 4] = [
+        SizeClass::new("kmalloc-32", 32),
+        SizeClass::new("kmalloc-64", 64),
+        SizeClass::new("kmalloc-96", 96),
+        SizeClass::new("kmalloc-128", 128),
+    ];
+    let system = new_system_on(
+        &FRAMES,
+        KmallocConfig {
+            size_classes: &CLASSES,
+            ..Default::default()
+        },
+    );
+    // off-slab descriptors whose free list is larger than any size class
+    let options = CacheOptions {
+        debug: DebugFlags::STORE_USER,
+        ..Default::default()
+    };
+    let cache = SlabCache::<BigObj>::with_options("meta_fail", system, options).unwrap();
+    let before = FRAMES.outstanding.load(Ordering::Relaxed);
+    // every failure point while growing gives back what it took
+    let mut budget = 0;
+    let obj = loop {
+        FRAMES.budget.store(budget, Ordering::Relaxed);
+        match cache.alloc() {
+            Ok(obj) => break obj as *mut BigObj,
+            Err(err) => assert_eq!(err, SlabError::CantAllocFrame),
+        }
+        assert_eq!(FRAMES.outstanding.load(Ordering::Relaxed), before);
+        budget += 1;
+    };
+    assert!(budget > 1);
+    FRAMES.budget.store(usize::MAX, Ordering::Relaxed);
+    assert!(cache.dealloc(unsafe { &mut *obj }).is_ok());
+    assert!(cache.validate().is_ok());
+}
+
+#[test]
+fn test_create_cache_failure() {
+    static FRAMES: LimitedProvider = LimitedProvider {
+        budget: AtomicUsize::new(usize::MAX),
+        outstanding: AtomicUsize::new(0),
+    };
+    let system = new_system_on(&FRAMES, KmallocConfig::default());
+    // the per-cpu entries need their own frames
+    let options = CacheOptions {
+        tunables: CacheTunables {
+            limit: 240,
+            batch_count: 60,
+            shared: 480,
+        },
+        ..Default::default()
+    };
+    system.shrink();
+    let before = FRAMES.outstanding.load(Ordering::Relaxed);
+    let mut budget = 0;
+    let mut cache = loop {
+        FRAMES.budget.store(budget, Ordering::Relaxed);
+        match SlabCache::<TestObj>::with_options("create_fail", system, options) {
+            Ok(cache) => break cache,
+            Err(err) => assert_eq!(err, SlabError::CantAllocFrame),
+        }
+        // a failed creation leaves nothing behind
+        assert!(system.get_cache_info("create_fail").is_none());
+        system.shrink();
+        assert_eq!(FRAMES.outstanding.load(Ordering::Relaxed), before);
+        budget += 1;
+    };
+    assert!(budget > 0);
+    FRAMES.budget.store(usize::MAX, Ordering::Relaxed);
+    assert!(system.validate().is_ok());
+    assert_eq!(cache.destroy(), Ok(Default::default()));
+}
+
+#[test]
+fn test_grow_zeroed() {
+    let system = new_system();
+    let allocator = SlabAllocator::new_in(system);
+    let old = Layout::from_size_align(20, 8).unwrap();
+    let ptr = allocator.allocate(old).unwrap();
+    unsafe { ptr.cast::<u8>().as_ptr().write_bytes(0xff, ptr.len()) };
+    // grows in place, the slack of the slot must be zeroed too
+    let new = Layout::from_size_align(24, 8).unwrap();
+    let grown = unsafe { allocator.grow_zeroed(ptr.cast(), old, new) }.unwrap();
+    assert_eq!(grown.cast::<u8>(), ptr.cast::<u8>());
+    assert!(grown.len() > new.size());
+    let bytes = unsafe { grown.as_ref() };
+    assert!(bytes[..20].iter().all(|&b| b == 0xff));
+    assert!(bytes[20..].iter().all(|&b| b == 0));
+    unsafe { allocator.deallocate(grown.cast(), new) };
+}
+
+#[test]
+fn test_store_user_allocator() {
+    let system = new_system_with(KmallocConfig {
+        debug: DebugFlags::STORE_USER,
+        ..Default::default()
+    });
+    let allocator = SlabAllocator::new_in(system);
+    let layout = Layout::from_size_align(40, 8).unwrap();
+    let (ptr, line) = (unsafe { allocator.alloc(layout) }, line!());
+    let track = system.alloc_track(ptr).unwrap();
+    assert_eq!(track.caller.file(), file!());
+    assert_eq!(track.caller.line(), line);
+    let (grown, line) = (unsafe { allocator.realloc(ptr, layout, 200) }, line!());
+    assert_eq!(system.alloc_track(grown).unwrap().caller.line(), line);
+    unsafe { allocator.dealloc(grown, Layout::from_size_align(200, 8).unwrap()) };
+    let (ptr, line) = (allocator.allocate(layout).unwrap(), line!());
+    let track = system.alloc_track(ptr.cast::<u8>().as_ptr()).unwrap();
+    assert_eq!(track.caller.file(), file!());
+    assert_eq!(track.caller.line(), line);
+    unsafe { allocator.deallocate(ptr.cast(), layout) };
+}
+
+#[test]
+fn test_set_tunables_concurrent() {
+    let system = new_system();
+    let layout = Layout::from_size_align(64, 8).unwrap();
+    std::thread::scope(|scope| {
+        for _ in 0..4 {
+            scope.spawn(|| {
+                for _ in 0..2000 {
+                    let ptr = system.kmalloc(layout, AllocFlags::empty()).unwrap();
+                    assert!(system.kfree(ptr).is_ok());
+                }
+            });
+        }
+        for i in 0..200 {
+            let tunables = CacheTunables {
+                limit: 16 + i % 32,
+                batch_count: 8,
+                shared: 32,
+            };
+            assert!(system.set_tunables("malloc-64", tunables).is_ok());
+        }
+    });
+    assert!(system.validate().is_ok());
+}
+
+thread_local! {
+    static THREAD_CPU: std::cell::Cell<usize> = const { std::cell::Cell::new(0) };
+}
+
+/// 每个线程使用自己设置的cpu id
+struct ThreadCpu;
+
+impl CpuTopology for ThreadCpu {
+    fn current_cpu_id(&self) -> usize {
+        THREAD_CPU.with(|cpu| cpu.get())
+    }
+}
+
+#[test]
+fn test_create_destroy_concurrent() {
+    static NAMES: [&str; 4] = ["concurrent_0", "concurrent_1", "concurrent_2", "concurrent_3"];
+    let system = new_system();
+    let stop = AtomicBool::new(false);
+    std::thread::scope(|scope| {
+        for name in NAMES {
+            scope.spawn(move || {
+                for _ in 0..200 {
+                    let mut cache = SlabCache::<TestObj>::new_in(name, system).unwrap();
+                    let obj = cache.alloc().unwrap();
+                    assert!(cache.dealloc(obj).is_ok());
+                    assert_eq!(cache.destroy(), Ok(Default::default()));
+                }
+            });
+        }
+        // walking the cache list while caches come and go
+        scope.spawn(|| {
+            while !stop.load(Ordering::Relaxed) {
+                system.shrink();
+                assert!(system.validate().is_ok());
+                for name in NAMES {
+                    let _ = system.get_cache_info(name);
+                }
+            }
+        });
+        // two threads racing for the same name, only one of them wins
+        let results: Vec<_> = (0..2)
+            .map(|_| scope.spawn(|| SlabCache::<TestObj>::new_in("concurrent_same", system)))
+            .collect::<Vec<_>>()
+            .into_iter()
+            .map(|handle| handle.join().unwrap())
+            .collect();
+        assert_eq!(results.iter().filter(|result| result.is_ok()).count(), 1);
+        assert!(results
+            .iter()
+            .any(|result| matches!(result, Err(SlabError::NameDuplicate))));
+        stop.store(true, Ordering::Relaxed);
+    });
+    assert!(system.validate().is_ok());
+}
+
+#[test]
+fn test_set_tunables_batch_multi_cpu() {
+    let mut system = leak_system();
+    system.as_mut().init(4096, 64, 4, &TestProvider, &ThreadCpu).unwrap();
+    let system = system.into_ref().get_ref();
+    let layout = Layout::from_size_align(64, 8).unwrap();
+    let stop = AtomicBool::new(false);
+    std::thread::scope(|scope| {
+        for cpu in 0..4 {
+            let stop = &stop;
+            scope.spawn(move || {
+                THREAD_CPU.with(|id| id.set(cpu));
+                let mut ptrs = Vec::new();
+                let mut round = 0;
+                while !stop.load(Ordering::Relaxed) {
+                    round += 1;
+                    // 一次释放多个对象，让本地高速缓存溢出到共享高速缓存
+                    for _ in 0..(round % 48 + 1) {
+                        ptrs.push(system.kmalloc(layout, AllocFlags::empty()).unwrap());
+                    }
+                    for ptr in ptrs.drain(..) {
+                        assert!(system.kfree(ptr).is_ok());
+                    }
+                }
+            });
+        }
+        for i in 0..2000 {
+            // batch_count在变大与变小之间交替
+            let batch_count = if i % 2 == 0 { 2 } else { 16 };
+            let tunables = CacheTunables {
+                limit: 16 + i % 32,
+                batch_count,
+                shared: 16 + i % 17,
+            };
+            assert!(system.set_tunables("malloc-64", tunables).is_ok());
+        }
+        stop.store(true, Ordering::Relaxed);
+    });
+    assert!(system.validate().is_ok());
 }